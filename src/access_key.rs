@@ -0,0 +1,224 @@
+use actix_web::web;
+use argon2::Argon2;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHasher, PasswordVerifier, SaltString};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::LazyLock;
+use subtle::ConstantTimeEq;
+
+/// Prefix of a stored hash, so the scheme can be changed later
+const HASH_SCHEME: &str = "sha256";
+
+/// Start of an argon2 hash in PHC string format
+const ARGON2_PREFIX: &str = "$argon2";
+
+/// Number of random bytes in a salt
+const SALT_LEN: usize = 16;
+
+/// Shortest access key a caller can choose, generated keys are 48 characters
+pub const MIN_CHOSEN_KEY_LEN: usize = 32;
+
+/// Fewest different characters a caller chosen access key can have
+const MIN_CHOSEN_KEY_CHARS: usize = 10;
+
+/// Create a new random access key
+pub fn generate_access_key() -> String {
+    (0..48)
+        .map(|_| rand::rng().random_range('A'..='Z'))
+        .collect()
+}
+
+/// Is a caller chosen access key long and varied enough to be as hard to guess as a generated one
+pub fn is_strong_key(key: &str) -> bool {
+    key.chars().count() >= MIN_CHOSEN_KEY_LEN
+        && key.chars().collect::<HashSet<_>>().len() >= MIN_CHOSEN_KEY_CHARS
+}
+
+/// Compare two secrets without leaking where they differ through timing
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_with_salt(salt: &str, key: &str) -> String {
+    let mut sha = Sha256::new();
+    sha.update(salt.as_bytes());
+    sha.update(key.as_bytes());
+    to_hex(&sha.finalize())
+}
+
+/// A salted hash of an access key, stored as `sha256$<salt>$<hash>`
+/// Generated keys and chosen ones that pass [is_strong_key] are too hard to guess for a leaked hash to be brute forced,
+/// so a single round of SHA-256 is enough. Keys stored before that check might be weak, those are hashed with argon2
+/// like passwords instead
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct AccessKeyHash(String);
+
+impl AccessKeyHash {
+    pub fn new(key: &str) -> Self {
+        let salt: [u8; SALT_LEN] = rand::rng().random();
+        let salt = to_hex(&salt);
+        let hash = hash_with_salt(&salt, key);
+        Self(format!("{}${}${}", HASH_SCHEME, salt, hash))
+    }
+
+    /// Hash a key that was chosen without checking its strength, with argon2 if it's weak
+    pub fn new_unchecked(key: &str) -> anyhow::Result<Self> {
+        if is_strong_key(key) {
+            Ok(Self::new(key))
+        } else {
+            Ok(Self(PasswordHash::new(key)?.0))
+        }
+    }
+
+    /// Check if the given key matches this hash, in constant time
+    pub fn verify(&self, key: &str) -> bool {
+        if self.0.starts_with(ARGON2_PREFIX) {
+            return verify_argon2(&self.0, key);
+        }
+
+        let mut parts = self.0.splitn(3, '$');
+        let (Some(HASH_SCHEME), Some(salt), Some(expected)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+
+        let actual = hash_with_salt(salt, key);
+        actual.as_bytes().ct_eq(expected.as_bytes()).into()
+    }
+}
+
+impl fmt::Debug for AccessKeyHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AccessKeyHash(<redacted>)")
+    }
+}
+
+/// An argon2 hash of a user chosen password, in PHC string format
+/// Unlike access keys, passwords can be weak so they need a slow hash
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct PasswordHash(String);
+
+impl PasswordHash {
+    pub fn new(password: &str) -> anyhow::Result<Self> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+        Ok(Self(hash.to_string()))
+    }
+
+    /// [Self::new] on the blocking thread pool, argon2 is slow on purpose so it mustn't hold up the async workers
+    pub async fn new_blocking(password: String) -> anyhow::Result<Self> {
+        web::block(move || Self::new(&password)).await?
+    }
+
+    /// A hash of no one's password, to check against when there is no real hash so it takes as long as a real check
+    pub fn dummy() -> &'static Self {
+        static DUMMY: LazyLock<PasswordHash> = LazyLock::new(|| {
+            PasswordHash::new(&generate_access_key()).expect("hash dummy password")
+        });
+        &DUMMY
+    }
+
+    /// Check if the given password matches this hash
+    pub fn verify(&self, password: &str) -> bool {
+        verify_argon2(&self.0, password)
+    }
+
+    /// [Self::verify] on the blocking thread pool
+    pub async fn verify_blocking(&self, password: String) -> anyhow::Result<bool> {
+        let hash = self.clone();
+        Ok(web::block(move || hash.verify(&password)).await?)
+    }
+}
+
+fn verify_argon2(hash: &str, password: &str) -> bool {
+    argon2::PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_keys_are_strong() {
+        let key = generate_access_key();
+        assert!(is_strong_key(&key));
+        assert_ne!(key, generate_access_key());
+    }
+
+    #[test]
+    fn short_or_repetitive_keys_are_weak() {
+        assert!(!is_strong_key("hunter2"));
+        assert!(!is_strong_key(&"ab".repeat(MIN_CHOSEN_KEY_LEN)));
+        assert!(is_strong_key("correct-horse-battery-staple-42!"));
+    }
+
+    #[test]
+    fn access_key_hash_only_verifies_its_key() {
+        let key = generate_access_key();
+        let hash = AccessKeyHash::new(&key);
+
+        assert!(hash.verify(&key));
+        assert!(!hash.verify(&generate_access_key()));
+        assert!(!hash.verify(""));
+        // Salted, so the same key never hashes the same twice
+        assert!(hash != AccessKeyHash::new(&key));
+    }
+
+    #[test]
+    fn weak_unchecked_keys_are_hashed_with_argon2() {
+        let hash = AccessKeyHash::new_unchecked("hunter2").unwrap();
+        assert!(hash.0.starts_with(ARGON2_PREFIX));
+        assert!(hash.verify("hunter2"));
+        assert!(!hash.verify("hunter3"));
+
+        let key = generate_access_key();
+        let hash = AccessKeyHash::new_unchecked(&key).unwrap();
+        assert!(hash.0.starts_with(HASH_SCHEME));
+        assert!(hash.verify(&key));
+    }
+
+    #[test]
+    fn empty_hash_verifies_nothing() {
+        assert!(!AccessKeyHash::default().verify(""));
+    }
+
+    #[test]
+    fn password_hash_only_verifies_its_password() {
+        let hash = PasswordHash::new("hunter2").unwrap();
+        assert!(hash.verify("hunter2"));
+        assert!(!hash.verify("hunter3"));
+        assert_eq!(format!("{:?}", hash), "PasswordHash(<redacted>)");
+    }
+
+    #[actix_web::test]
+    async fn password_hash_works_off_the_async_workers() {
+        let hash = PasswordHash::new_blocking("hunter2".to_string())
+            .await
+            .unwrap();
+        assert!(hash.verify_blocking("hunter2".to_string()).await.unwrap());
+        assert!(!hash.verify_blocking("hunter3".to_string()).await.unwrap());
+    }
+}
@@ -0,0 +1,61 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+
+/// Downloads of a blob, or group of blobs, on a single day
+#[derive(Serialize, Debug, Default, Clone, Copy)]
+pub struct DailyStats {
+    pub downloads: u64,
+    pub bytes: u64,
+}
+
+impl DailyStats {
+    pub fn to_bytes(self) -> [u8; 16] {
+        let mut out = [0u8; 16];
+        out[..8].copy_from_slice(&self.downloads.to_be_bytes());
+        out[8..].copy_from_slice(&self.bytes.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut downloads = [0u8; 8];
+        let mut bytes = [0u8; 8];
+        if data.len() == 16 {
+            downloads.copy_from_slice(&data[..8]);
+            bytes.copy_from_slice(&data[8..]);
+        }
+        Self {
+            downloads: u64::from_be_bytes(downloads),
+            bytes: u64::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn add(&mut self, other: DailyStats) {
+        self.downloads += other.downloads;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Sled merge operator that sums [DailyStats], so concurrent downloads never lose an update
+pub fn merge_daily_stats(_key: &[u8], old: Option<&[u8]>, new: &[u8]) -> Option<Vec<u8>> {
+    let mut stats = old.map(DailyStats::from_bytes).unwrap_or_default();
+    stats.add(DailyStats::from_bytes(new));
+    Some(stats.to_bytes().to_vec())
+}
+
+/// Build the key for the stats of a blob on a given day
+/// Keys are the blob path followed by the date, so all the days of a blob, or all the blobs in a bucket, can be found
+/// with a prefix scan
+pub fn stats_key(blob_key: &[u8], day: NaiveDate) -> Vec<u8> {
+    let mut key = blob_key.to_vec();
+    key.push(0);
+    key.extend_from_slice(day.format("%Y-%m-%d").to_string().as_bytes());
+    key
+}
+
+/// Split a key made by [stats_key] back into the blob path and date
+pub fn parse_stats_key(key: &[u8]) -> Option<(&[u8], NaiveDate)> {
+    let split = key.iter().rposition(|b| *b == 0)?;
+    let day = std::str::from_utf8(&key[split + 1..]).ok()?;
+    let day = NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+    Some((&key[..split], day))
+}
@@ -0,0 +1,350 @@
+//! The authorization layer
+//! Handlers ask an [Authorizer] whether a request may do something, rather than checking credentials themselves.
+//! A request can carry any of:
+//! - the global bucket creation key, or global upload key, in `Authorization: Bearer`
+//! - a per-bucket key in `Authorization: Bearer`, see [BucketKeyKind]
+//! - a blob's access key in `X-Blob-Access-Key`
+//! - an API token or a user session in `Authorization: Bearer`, told apart from keys by their prefix
+//! - a presigned URL, only on the handlers that allow it
+//! - a tenant's admin key in `Authorization: Bearer`, for requests routed to that tenant
+//!
+//! Keys can also be given in `?auth=` for older clients, depending on [QueryAuthPolicy]
+
+use crate::access_key::constant_time_eq;
+use crate::bucket_config::{BucketKeyKind, check_bucket_key};
+use crate::metadata::{BlobMetadata, MetadataManager};
+use crate::path::{BucketPath, PathExists};
+use crate::presign::{PresignMethod, PresignQuery};
+use crate::settings::{AppSettings, QueryAuthPolicy};
+use crate::tenant::{Tenant, request_tenant};
+use crate::token::{self, TOKEN_PREFIX, TokenOp, bearer_token};
+use crate::user::{Role, SESSION_PREFIX, User};
+use actix_web::web::Query;
+use actix_web::{HttpMessage, HttpRequest};
+use serde::Deserialize;
+
+/// Things a request can be allowed to do to a bucket or blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read private or embargoed blobs
+    Read,
+    /// List or search the blobs of a bucket
+    List,
+    /// Upload new blobs
+    Upload,
+    /// Change the metadata of blobs, or share them
+    Modify,
+    Delete,
+    /// Manage the bucket itself
+    Admin,
+}
+
+impl Permission {
+    fn key_kind(self) -> BucketKeyKind {
+        match self {
+            Permission::Read | Permission::List => BucketKeyKind::Read,
+            Permission::Upload => BucketKeyKind::Upload,
+            Permission::Modify | Permission::Delete | Permission::Admin => BucketKeyKind::Admin,
+        }
+    }
+
+    fn token_op(self) -> Option<TokenOp> {
+        match self {
+            Permission::Read => Some(TokenOp::Read),
+            Permission::List => Some(TokenOp::List),
+            Permission::Upload => Some(TokenOp::Upload),
+            Permission::Delete => Some(TokenOp::Delete),
+            Permission::Modify | Permission::Admin => None,
+        }
+    }
+
+    /// The lowest role that has this permission
+    fn role(self) -> Role {
+        match self {
+            Permission::Read | Permission::List => Role::Reader,
+            Permission::Upload | Permission::Modify | Permission::Delete => Role::Writer,
+            Permission::Admin => Role::BucketOwner,
+        }
+    }
+
+    fn presign_method(self) -> Option<PresignMethod> {
+        match self {
+            Permission::Read => Some(PresignMethod::Get),
+            Permission::Upload => Some(PresignMethod::Put),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct KeyQuery {
+    auth: Option<String>,
+}
+
+/// The key given in `?auth=`, if the [QueryAuthPolicy] allows it
+fn query_key(req: &HttpRequest, settings: &AppSettings) -> Option<String> {
+    let key = Query::<KeyQuery>::from_query(req.query_string())
+        .ok()?
+        .into_inner()
+        .auth?;
+
+    match settings.query_auth {
+        QueryAuthPolicy::Allow => Some(key),
+        QueryAuthPolicy::Warn => {
+            tracing::warn!(
+                "Key given in query string for {}, use the Authorization header instead",
+                req.path()
+            );
+            Some(key)
+        }
+        QueryAuthPolicy::Reject => {
+            tracing::warn!(
+                "Ignoring key given in query string for {}, use the Authorization header instead",
+                req.path()
+            );
+            None
+        }
+    }
+}
+
+/// Whether the credentials a request presented got it anything, kept in the request's extensions for the rate limiter
+/// Handlers answer failed checks with a 404 when they're hiding a blob, so the status alone doesn't show a guessed key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResult {
+    /// Every check failed
+    Refused,
+    /// At least one check passed
+    Allowed,
+}
+
+/// The credentials of one request, see the module docs
+pub struct Authorizer<'a> {
+    req: &'a HttpRequest,
+    settings: &'a AppSettings,
+    metadata: &'a MetadataManager,
+    key: Option<String>,
+    user: Option<User>,
+    presigned: Option<PresignMethod>,
+    /// The tenant the request was routed to, if it exists
+    tenant: Option<Tenant>,
+}
+
+impl<'a> Authorizer<'a> {
+    pub fn new(
+        req: &'a HttpRequest,
+        settings: &'a AppSettings,
+        metadata: &'a MetadataManager,
+    ) -> Self {
+        let header_key = bearer_token(req)
+            .filter(|t| !t.starts_with(TOKEN_PREFIX) && !t.starts_with(SESSION_PREFIX))
+            .map(str::to_string);
+
+        let key = header_key.or_else(|| query_key(req, settings));
+
+        let user = bearer_token(req)
+            .filter(|t| t.starts_with(SESSION_PREFIX))
+            .and_then(|t| match metadata.get_session(t) {
+                Ok(session) => session,
+                Err(e) => {
+                    tracing::warn!("Failed to get session {}", e);
+                    None
+                }
+            })
+            .and_then(|session| metadata.get_user(&session.username).ok().flatten());
+
+        let tenant = request_tenant(req).and_then(|name| match metadata.get_tenant(&name) {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                tracing::warn!("Failed to get tenant {}", e);
+                None
+            }
+        });
+
+        Self {
+            req,
+            settings,
+            metadata,
+            key,
+            user,
+            presigned: None,
+            tenant,
+        }
+    }
+
+    /// Accept a presigned URL for the given method, for handlers that serve or accept blob content
+    pub fn allow_presigned(mut self, method: PresignMethod) -> Self {
+        self.presigned = Some(method);
+        self
+    }
+
+    /// The logged in user, if any
+    pub fn user(&self) -> Option<&User> {
+        self.user.as_ref()
+    }
+
+    /// Can the request do anything, either with the global bucket creation key or as an admin user
+    pub fn is_super_admin(&self) -> bool {
+        self.key
+            .as_deref()
+            .is_some_and(|k| constant_time_eq(k, &self.settings.bucket_creation_key))
+            || self.user.as_ref().is_some_and(User::is_admin)
+    }
+
+    /// Can the request do anything within the namespace it was routed to
+    /// This is a super admin, or the admin key of the request's tenant
+    pub fn is_namespace_admin(&self) -> bool {
+        self.is_super_admin()
+            || self.tenant.as_ref().is_some_and(|tenant| {
+                self.key
+                    .as_deref()
+                    .is_some_and(|k| tenant.admin_key.verify(k))
+            })
+    }
+
+    fn tenant_name(&self) -> Option<&str> {
+        self.tenant.as_ref().map(|t| t.name.as_str())
+    }
+
+    /// Can the request do `permission` to a whole bucket
+    pub fn can(
+        &self,
+        bucket: &BucketPath<PathExists>,
+        bucket_name: &str,
+        permission: Permission,
+    ) -> bool {
+        self.check(bucket, bucket_name, None, None, permission)
+    }
+
+    /// Does the request have the blob's own key, which can do anything to it except replace it
+    /// Unlike the other checks this doesn't read the metadata DB, so it can be used inside a metadata transaction
+    pub fn has_blob_key(&self, meta: &BlobMetadata, permission: Permission) -> bool {
+        let allowed = permission != Permission::Upload
+            && permission != Permission::Admin
+            && self
+                .req
+                .headers()
+                .get("X-Blob-Access-Key")
+                .and_then(|k| k.to_str().ok())
+                .is_some_and(|k| meta.check_access_key(k));
+        // Callers check other credentials first, which refused the request if they needed this key
+        if allowed {
+            self.record(true);
+        }
+        allowed
+    }
+
+    /// Can the request do `permission` to a blob, `meta` is `None` if the blob doesn't exist yet
+    pub fn can_file(
+        &self,
+        bucket: &BucketPath<PathExists>,
+        bucket_name: &str,
+        file_name: &str,
+        meta: Option<&BlobMetadata>,
+        permission: Permission,
+    ) -> bool {
+        self.check(bucket, bucket_name, Some(file_name), meta, permission)
+    }
+
+    /// Does the request carry a credential that can be guessed
+    fn has_credential(&self) -> bool {
+        self.key.is_some()
+            || bearer_token(self.req).is_some()
+            || self.req.headers().contains_key("X-Blob-Access-Key")
+            || (self.presigned.is_some() && self.req.query_string().contains("signature="))
+    }
+
+    /// Note the result of a check for the rate limiter, a request that passed any check isn't a failed one
+    fn record(&self, allowed: bool) {
+        let mut extensions = self.req.extensions_mut();
+        if allowed {
+            extensions.insert(AuthResult::Allowed);
+        } else if extensions.get::<AuthResult>().is_none() {
+            extensions.insert(AuthResult::Refused);
+        }
+    }
+
+    fn check(
+        &self,
+        bucket: &BucketPath<PathExists>,
+        bucket_name: &str,
+        file_name: Option<&str>,
+        meta: Option<&BlobMetadata>,
+        permission: Permission,
+    ) -> bool {
+        let allowed = self.check_credentials(bucket, bucket_name, file_name, meta, permission);
+        if self.has_credential() {
+            self.record(allowed);
+        }
+        allowed
+    }
+
+    fn check_credentials(
+        &self,
+        bucket: &BucketPath<PathExists>,
+        bucket_name: &str,
+        file_name: Option<&str>,
+        meta: Option<&BlobMetadata>,
+        permission: Permission,
+    ) -> bool {
+        if self.is_namespace_admin() {
+            return true;
+        }
+
+        if let Some(user) = &self.user
+            && user
+                .role_in(self.tenant_name(), bucket_name)
+                .is_some_and(|role| role >= permission.role())
+        {
+            return true;
+        }
+
+        if let Some(meta) = meta
+            && self.has_blob_key(meta, permission)
+        {
+            return true;
+        }
+
+        if let Some(op) = permission.token_op()
+            && token::request_allows(
+                self.req,
+                self.settings,
+                self.metadata,
+                self.tenant_name(),
+                bucket_name,
+                file_name,
+                op,
+            )
+        {
+            return true;
+        }
+
+        if let (Some(method), Some(file_name)) = (self.presigned, file_name)
+            && permission.presign_method() == Some(method)
+            && Query::<PresignQuery>::from_query(self.req.query_string()).is_ok_and(|q| {
+                q.verify(
+                    self.settings,
+                    method,
+                    self.tenant_name(),
+                    bucket_name,
+                    file_name,
+                )
+            })
+        {
+            return true;
+        }
+
+        let Some(key) = &self.key else {
+            return false;
+        };
+
+        match self.metadata.get_bucket_config(bucket) {
+            Ok(config) => {
+                check_bucket_key(self.settings, config.as_ref(), key, permission.key_kind())
+            }
+            Err(e) => {
+                tracing::warn!("Failed to get bucket config {}", e);
+                false
+            }
+        }
+    }
+}
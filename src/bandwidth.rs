@@ -0,0 +1,224 @@
+//! Bandwidth throttling and egress accounting
+//! Downloads are streamed in chunks, and each chunk waits until it fits within the bandwidth caps of the download
+//! itself and of its bucket, which all concurrent downloads from the bucket share. Caps are in bytes per second and
+//! allow up to a second's worth of bursting. The bytes actually sent are recorded against the bucket once the download
+//! finishes or the client goes away, see [MetadataManager::record_egress]
+//!
+//! - `BANDWIDTH_PER_DOWNLOAD` caps every single download
+//! - `BANDWIDTH_PER_BUCKET` caps buckets that don't set their own cap in their config
+
+use crate::metadata::MetadataManager;
+use actix_web::body::SizedStream;
+use actix_web::web::{Bytes, Data};
+use anyhow::Context;
+use futures::Stream;
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+
+/// Largest chunk a download is sent in
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Smallest chunk a throttled download is sent in
+const MIN_CHUNK_SIZE: usize = 1024;
+
+/// Buckets tracked before idle ones are forgotten
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BandwidthSettings {
+    /// Bytes per second of a single download
+    pub per_download: Option<u64>,
+    /// Bytes per second of all downloads from a bucket, unless it sets its own
+    pub per_bucket: Option<u64>,
+}
+
+impl BandwidthSettings {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let read = |name: &str| -> anyhow::Result<Option<u64>> {
+            match env::var(name) {
+                Ok(v) if !v.is_empty() => Ok(Some(
+                    v.parse::<u64>()
+                        .ok()
+                        .filter(|r| *r > 0)
+                        .with_context(|| format!("Invalid {}, expected bytes per second", name))?,
+                )),
+                _ => Ok(None),
+            }
+        };
+
+        Ok(Self {
+            per_download: read("BANDWIDTH_PER_DOWNLOAD")?,
+            per_bucket: read("BANDWIDTH_PER_BUCKET")?,
+        })
+    }
+}
+
+/// Bytes that can be sent right away, refilled at the cap's rate up to a second's worth
+#[derive(Debug)]
+struct ByteBucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl ByteBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            available: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Take `bytes`, going into debt if there aren't enough, and return how long to wait for the debt to be paid off
+    fn reserve(&mut self, bytes: usize, rate: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * rate).min(rate) - bytes as f64;
+        self.updated = now;
+
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / rate)
+        }
+    }
+
+    fn is_full(&self, rate: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.available + elapsed * rate as f64 >= rate as f64
+    }
+}
+
+/// Bandwidth shared by the downloads of each bucket, for every worker
+pub struct BandwidthLimiter {
+    settings: BandwidthSettings,
+    buckets: Mutex<HashMap<Vec<u8>, (ByteBucket, u64)>>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(settings: BandwidthSettings) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cap of a bucket, its own if it has one or the default
+    pub fn bucket_rate(&self, bucket_limit: Option<u64>) -> Option<u64> {
+        bucket_limit.or(self.settings.per_bucket)
+    }
+
+    fn reserve_bucket(&self, bucket_key: &[u8], bytes: usize, rate: u64, now: Instant) -> Duration {
+        let mut buckets = self.buckets.lock().expect("bandwidth lock");
+
+        // Buckets that have refilled are the same as ones that haven't been downloaded from
+        if buckets.len() >= MAX_TRACKED_BUCKETS {
+            buckets.retain(|_, (bucket, rate)| !bucket.is_full(*rate, now));
+        }
+
+        let (bucket, bucket_rate) = buckets
+            .entry(bucket_key.to_vec())
+            .or_insert_with(|| (ByteBucket::new(rate, now), rate));
+        *bucket_rate = rate;
+        bucket.reserve(bytes, rate, now)
+    }
+}
+
+/// Records the bytes a download sent against its bucket when it's dropped, however the download ended
+struct EgressRecorder {
+    metadata: Data<MetadataManager>,
+    bucket_key: Vec<u8>,
+    bytes: u64,
+}
+
+impl Drop for EgressRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.metadata.record_egress(&self.bucket_key, self.bytes) {
+            tracing::warn!("Failed to record egress {}", e);
+        }
+    }
+}
+
+struct Download {
+    file: tokio::fs::File,
+    limiter: Data<BandwidthLimiter>,
+    /// Cap of the whole bucket, shared with its other downloads
+    bucket_rate: Option<u64>,
+    /// Cap of just this download, with its own allowance
+    connection: Option<(ByteBucket, u64)>,
+    chunk_size: usize,
+    egress: EgressRecorder,
+}
+
+impl Download {
+    async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut buf = vec![0u8; self.chunk_size];
+        let read = self.file.read(&mut buf).await?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.truncate(read);
+
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        if let Some((bucket, rate)) = &mut self.connection {
+            wait = wait.max(bucket.reserve(read, *rate, now));
+        }
+        if let Some(rate) = self.bucket_rate {
+            wait = wait.max(
+                self.limiter
+                    .reserve_bucket(&self.egress.bucket_key, read, rate, now),
+            );
+        }
+        if !wait.is_zero() {
+            actix_rt::time::sleep(wait).await;
+        }
+
+        self.egress.bytes += read as u64;
+        Ok(Some(Bytes::from(buf)))
+    }
+}
+
+/// Stream `size` bytes of `file` as a response body, throttled to the caps of the download and its bucket
+pub fn throttled_body(
+    file: std::fs::File,
+    size: u64,
+    metadata: Data<MetadataManager>,
+    limiter: Data<BandwidthLimiter>,
+    bucket_key: Vec<u8>,
+    bucket_limit: Option<u64>,
+) -> SizedStream<impl Stream<Item = io::Result<Bytes>>> {
+    let bucket_rate = limiter.bucket_rate(bucket_limit);
+    let connection_rate = limiter.settings.per_download;
+
+    // Smaller chunks for slower caps, so throttled downloads trickle rather than stall
+    let chunk_size = match bucket_rate.into_iter().chain(connection_rate).min() {
+        Some(rate) => usize::try_from(rate / 4)
+            .unwrap_or(MAX_CHUNK_SIZE)
+            .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+        None => MAX_CHUNK_SIZE,
+    };
+
+    let now = Instant::now();
+    let download = Download {
+        file: tokio::fs::File::from_std(file),
+        limiter,
+        bucket_rate,
+        connection: connection_rate.map(|rate| (ByteBucket::new(rate, now), rate)),
+        chunk_size,
+        egress: EgressRecorder {
+            metadata,
+            bucket_key,
+            bytes: 0,
+        },
+    };
+
+    let stream = futures::stream::try_unfold(download, |mut download| async move {
+        Ok(download.next_chunk().await?.map(|chunk| (chunk, download)))
+    });
+
+    SizedStream::new(size, stream)
+}
@@ -1,18 +1,37 @@
+use crate::access_key::{MIN_CHOSEN_KEY_LEN, generate_access_key, is_strong_key};
+use crate::auth::{Authorizer, Permission};
+use crate::bucket_config::{BucketConfig, BucketKeyKind};
+use crate::disposition::{self, DISPOSITION_HEADER, Disposition};
 use crate::file_location::FileLocation;
-use crate::metadata::BlobMetadata;
+use crate::fulltext::{self, FullTextIndex};
 use crate::metadata::MetadataManager;
+use crate::metadata::{
+    BlobChecksums, BlobMetadata, TAGS_HEADER, USER_METADATA_HEADER_PREFIX, validate_user_metadata,
+};
+use crate::path::{BlobPath, PathExists};
+use crate::presign::{PresignMethod, PresignQuery};
+use crate::quota::{self, Quota, QuotaScope};
+use crate::redact::redact_headers;
+use crate::response_headers::ResponseHeaders;
 use crate::settings::AppSettings;
-use crate::{AWError, PathManager, StreamExt};
+use crate::sniff::{self, SNIFF_LEN};
+use crate::tenant::TenantPaths;
+use crate::upload_policy::{PolicyViolation, UploadPolicy};
+use crate::visibility::{self, VISIBILITY_HEADER, Visibility};
+use crate::{AWError, StreamExt};
 use actix_multipart::Multipart;
 use actix_web::HttpResponse;
 use actix_web::delete;
-use actix_web::put;
-use actix_web::web::{Data, Path as WebPath, Query};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::web::{Data, Json, Path as WebPath, Query};
 use actix_web::{HttpRequest, get};
+use actix_web::{patch, post, put};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sha1::Digest;
 use sha1::Sha1;
+use sha2::Sha256;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::Read;
 use std::ops::Deref;
@@ -23,7 +42,7 @@ use walkdir::WalkDir;
 
 #[derive(Deserialize)]
 pub struct BucketLocation {
-    name: String,
+    pub name: String,
 }
 
 #[derive(Serialize)]
@@ -35,22 +54,27 @@ pub struct Bucket {
 pub struct Blob {
     blob_name: String,
     blob_sha1: String,
+    /// Is this blob waiting for its publish date
+    #[serde(default)]
+    embargoed: bool,
 }
 
-#[derive(Deserialize)]
-pub struct CreateBucketQuery {
-    auth: String,
+/// Plaintext keys of a bucket, only ever returned when they are created
+#[derive(Serialize)]
+pub struct BucketKeys {
+    keys: BTreeMap<BucketKeyKind, String>,
 }
 
 #[get("/api/bucket/{name}/create")]
 pub async fn get_bucket_create(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
     file: WebPath<BucketLocation>,
-    auth: Query<CreateBucketQuery>,
+    req: HttpRequest,
     settings: Data<AppSettings>,
 ) -> Result<HttpResponse, AWError> {
-    if auth.auth != settings.bucket_creation_key {
-        return Ok(HttpResponse::BadRequest().finish());
+    if !Authorizer::new(&req, &settings, &metadata).is_namespace_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
     }
 
     let _span = tracing::info_span!("bucket_create").entered();
@@ -62,24 +86,217 @@ pub async fn get_bucket_create(
 
     tokio::fs::create_dir(&*path).await?;
 
-    Ok(HttpResponse::Ok().finish())
+    let (config, keys) = BucketConfig::new();
+    if let Err(e) = metadata.save_bucket_config(&path, &config) {
+        tracing::warn!("Failed to save config for bucket {}: {}", &file.name, e);
+        tokio::fs::remove_dir(&*path).await?;
+        return Ok(HttpResponse::InternalServerError().finish());
+    }
+
+    Ok(HttpResponse::Ok().json(BucketKeys {
+        keys: keys.into_iter().collect(),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RotateKeysRequest {
+    keys: BTreeSet<BucketKeyKind>,
+}
+
+/// Replace some of the keys of a bucket, needs admin permission on the bucket
+/// Buckets made by older versions have no keys, so all of their keys are issued the first time this is called
+#[post("/api/bucket/{name}/keys/rotate")]
+pub async fn post_bucket_rotate_keys(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    file: WebPath<BucketLocation>,
+    req: HttpRequest,
+    settings: Data<AppSettings>,
+    body: Json<RotateKeysRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_rotate_keys").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Admin) {
+        tracing::warn!("Not allowed to rotate bucket keys");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let res = metadata.update_bucket_config(&bucket, |config, exists| {
+        let kinds: Vec<BucketKeyKind> = if exists {
+            body.keys.iter().copied().collect()
+        } else {
+            config.created_at = Some(Utc::now());
+            BucketKeyKind::ALL.to_vec()
+        };
+
+        kinds
+            .into_iter()
+            .map(|kind| (kind, config.rotate_key(kind)))
+            .collect()
+    });
+
+    match res {
+        Ok(keys) => Ok(HttpResponse::Ok().json(BucketKeys { keys })),
+        Err(e) => {
+            tracing::warn!("Failed to rotate keys for bucket {}: {}", &file.name, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BucketConfigPatch {
+    visibility: Option<Visibility>,
+    /// Replaces the bucket's quota, needs an admin of the whole namespace
+    quota: Option<Quota>,
+    /// Replaces the bucket's upload policy
+    upload_policy: Option<UploadPolicy>,
+    /// Replaces the headers sent with downloads of blobs that don't set their own
+    response_headers: Option<ResponseHeaders>,
+    /// Bytes per second of all downloads from the bucket, `null` uses the default. Needs an admin of the namespace
+    #[serde(default, deserialize_with = "double_option")]
+    bandwidth_limit: Option<Option<u64>>,
+}
+
+#[derive(Serialize)]
+pub struct BucketConfigResult {
+    visibility: Visibility,
+    quota: Quota,
+    upload_policy: UploadPolicy,
+    response_headers: ResponseHeaders,
+    bandwidth_limit: Option<u64>,
+}
+
+/// Change the settings of a bucket, needs admin permission on the bucket
+#[patch("/api/bucket/{name}/config")]
+pub async fn patch_bucket_config(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    file: WebPath<BucketLocation>,
+    req: HttpRequest,
+    settings: Data<AppSettings>,
+    body: Json<BucketConfigPatch>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_patch_config").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let config = match metadata.get_bucket_config(&bucket) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("Failed to get config for bucket {}: {}", &file.name, e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+    if !authorizer.can(&bucket, &file.name, Permission::Admin) {
+        tracing::warn!("Not allowed to change bucket config");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    // Bucket owners can't raise their own quota or bandwidth
+    if (body.quota.is_some() || body.bandwidth_limit.is_some()) && !authorizer.is_namespace_admin()
+    {
+        tracing::warn!("Not allowed to change bucket quota or bandwidth");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if body.bandwidth_limit == Some(Some(0)) {
+        return Ok(HttpResponse::BadRequest().body("Invalid bandwidth limit"));
+    }
+
+    if let Some(policy) = &body.upload_policy
+        && policy
+            .required_headers
+            .iter()
+            .any(|name| HeaderName::from_bytes(name.as_bytes()).is_err())
+    {
+        return Ok(HttpResponse::BadRequest().body("Invalid required header"));
+    }
+
+    if let Some(Err(e)) = body
+        .response_headers
+        .as_ref()
+        .map(ResponseHeaders::validate)
+    {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    // Saving a config for a bucket without one would leave it with no usable keys
+    if config.is_none() {
+        return Ok(HttpResponse::BadRequest().body("Bucket has no keys, rotate its keys first"));
+    }
+
+    let res = metadata.update_bucket_config(&bucket, |config, _exists| {
+        if let Some(visibility) = body.visibility {
+            config.visibility = visibility;
+        }
+
+        if let Some(quota) = body.quota {
+            config.quota = quota;
+        }
+
+        if let Some(policy) = &body.upload_policy {
+            config.upload_policy = policy.clone();
+        }
+
+        if let Some(headers) = &body.response_headers {
+            config.response_headers = headers.clone();
+        }
+
+        if let Some(limit) = body.bandwidth_limit {
+            config.bandwidth_limit = limit;
+        }
+
+        BucketConfigResult {
+            visibility: config.visibility,
+            quota: config.quota,
+            upload_policy: config.upload_policy.clone(),
+            response_headers: config.response_headers.clone(),
+            bandwidth_limit: config.bandwidth_limit,
+        }
+    });
+
+    match res {
+        Ok(result) => Ok(HttpResponse::Ok().json(result)),
+        Err(e) => {
+            tracing::warn!("Failed to update config for bucket {}: {}", &file.name, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
 }
 
 #[get("/api/bucket/{name}/verify")]
 pub async fn bucket_verify(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
     file: WebPath<BucketLocation>,
+    req: HttpRequest,
+    settings: Data<AppSettings>,
 ) -> Result<HttpResponse, AWError> {
     let _span = tracing::info_span!("bucket_verify").entered();
 
     let mut blobs = Vec::new();
 
-    let path = match paths.get_bucket(Path::new(&file.name)) {
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
         Some(b) => b,
         None => return Ok(HttpResponse::InternalServerError().finish()),
     };
 
-    for e in WalkDir::new(&*path).into_iter().filter_map(|e| e.ok()) {
+    // Private and embargoed blobs are left out for anyone without a read credential
+    let can_read =
+        Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Read);
+
+    for e in WalkDir::new(&*bucket).into_iter().filter_map(|e| e.ok()) {
         let m = match e.metadata() {
             Ok(e) => e,
             Err(_e) => {
@@ -91,6 +308,25 @@ pub async fn bucket_verify(
         if m.is_file() {
             let path = e.path();
 
+            // Blob names are relative to the bucket, wherever the bucket's tenant keeps it
+            let path_string = match path.strip_prefix(&*bucket) {
+                Ok(p) => p.to_string_lossy().to_string(),
+                Err(_) => continue,
+            };
+
+            let meta = paths
+                .get_bucket_file(&bucket, Path::new(&path_string))
+                .and_then(|p| metadata.get_metadata(&p, false).ok());
+
+            if !can_read
+                && meta.as_ref().is_none_or(|m| {
+                    m.is_embargoed()
+                        || visibility::is_private(&metadata, &bucket, m).unwrap_or(true)
+                })
+            {
+                continue;
+            }
+
             let mut sha = Sha1::new();
 
             let mut blob_file = File::open(path)?;
@@ -100,14 +336,13 @@ pub async fn bucket_verify(
             sha.update(content.as_bytes());
 
             let hex_string = format!("{:X}", sha.finalize());
-            let path_string = path
-                .to_str()
-                .expect("Failed to convert path to string")
-                .replace(&format!("storage_root/{}/", &file.name), "");
+
+            let embargoed = meta.is_some_and(|m| m.is_embargoed());
 
             blobs.push(Blob {
                 blob_name: path_string,
                 blob_sha1: hex_string,
+                embargoed,
             })
         }
     }
@@ -118,15 +353,63 @@ pub async fn bucket_verify(
 #[derive(Serialize, Debug)]
 pub struct BucketDetails {
     pub content_type: String,
+    pub detected_content_type: Option<String>,
+    pub content_type_mismatch: bool,
     pub created_at: DateTime<Utc>,
     pub download_count: u32,
+    pub publish_at: Option<DateTime<Utc>>,
+    /// Size of the blob in bytes
+    pub size: u64,
+    pub checksums: Option<BlobChecksums>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub last_downloaded: Option<DateTime<Utc>>,
+    pub deletion_date: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    pub metadata: BTreeMap<String, String>,
+    pub tags: BTreeSet<String>,
+    pub visibility: Option<Visibility>,
+    pub original_filename: Option<String>,
+    pub disposition: Option<Disposition>,
+    pub response_headers: ResponseHeaders,
+}
+
+impl BucketDetails {
+    /// Collect the details of an existing blob from its metadata and the filesystem
+    fn for_blob(meta: BlobMetadata, path: &BlobPath<PathExists>) -> anyhow::Result<Self> {
+        let fs_meta = std::fs::metadata(path.deref())?;
+
+        Ok(BucketDetails {
+            content_type: meta.content_type,
+            detected_content_type: meta.detected_content_type,
+            content_type_mismatch: meta.content_type_mismatch,
+            created_at: meta.created_at.unwrap_or_else(Utc::now),
+            download_count: meta.download_count,
+            publish_at: meta.publish_at,
+            size: fs_meta.len(),
+            checksums: meta.checksums,
+            last_modified: fs_meta.modified().ok().map(DateTime::<Utc>::from),
+            last_downloaded: meta.last_downloaded,
+            deletion_date: meta.deletion_date,
+            expires_at: meta.expires_at,
+            max_downloads: meta.max_downloads,
+            metadata: meta.user_metadata,
+            tags: meta.tags,
+            visibility: meta.visibility,
+            original_filename: meta.original_filename,
+            disposition: meta.disposition,
+            response_headers: meta.response_headers,
+        })
+    }
 }
 
 #[get("/api/bucket/{bucket_name}/{file_name}/details")]
 pub async fn get_bucket_details(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
     metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
     file: WebPath<FileLocation>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, AWError> {
     let _span = tracing::info_span!("bucket_details").entered();
 
@@ -147,53 +430,243 @@ pub async fn get_bucket_details(
     };
 
     let meta = match metadata.get_metadata(&path, false) {
-        Ok(b) => b,
+        Ok(m) => m,
         Err(_e) => {
             tracing::warn!("Failed to find metadata {}", &path.deref().display());
             return Ok(HttpResponse::InternalServerError().finish());
         }
     };
 
-    Ok(HttpResponse::Ok().json(BucketDetails {
-        content_type: meta.content_type,
-        created_at: meta.created_at.unwrap_or_else(Utc::now),
-        download_count: meta.download_count,
-    }))
+    // Private blobs look like they don't exist without a read credential
+    match visibility::is_private(&metadata, &bucket, &meta) {
+        Ok(false) => {}
+        Ok(true)
+            if Authorizer::new(&req, &settings, &metadata).can_file(
+                &bucket,
+                &file.bucket_name,
+                &file.file_name,
+                Some(&meta),
+                Permission::Read,
+            ) => {}
+        Ok(true) => return Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to check visibility {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    match BucketDetails::for_blob(meta, &path) {
+        Ok(details) => Ok(HttpResponse::Ok().json(details)),
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Maximum number of blobs that can be requested in one batch
+const MAX_BATCH_DETAILS: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct BatchDetailsRequest {
+    files: Vec<String>,
+}
+
+/// Get the details of many blobs in a bucket at once, blobs that can't be found are `null`
+#[post("/api/bucket/{name}/details")]
+pub async fn post_bucket_batch_details(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    req: HttpRequest,
+    body: Json<BatchDetailsRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_batch_details").entered();
+
+    if body.files.len() > MAX_BATCH_DETAILS {
+        return Ok(HttpResponse::BadRequest().body("Too many files"));
+    }
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Failed to find bucket {}", &file.name);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let config = match metadata.get_bucket_config(&bucket) {
+        Ok(c) => c,
+        Err(e) => {
+            tracing::warn!("Failed to get bucket config {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    // Private blobs are left out unless the request can read the whole bucket
+    let can_read_private =
+        Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Read);
+    let default_visibility = config.map(|c| c.visibility).unwrap_or_default();
+
+    let details: BTreeMap<&String, Option<BucketDetails>> = body
+        .files
+        .iter()
+        .map(|name| {
+            let details = paths
+                .get_bucket_file(&bucket, Path::new(name))
+                .and_then(|p| {
+                    let meta = metadata.get_metadata(&p, false).ok()?;
+                    let private =
+                        meta.visibility.unwrap_or(default_visibility) == Visibility::Private;
+                    if private && !can_read_private {
+                        return None;
+                    }
+                    BucketDetails::for_blob(meta, &p).ok()
+                });
+            (name, details)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(details))
 }
 
 #[derive(Serialize)]
 struct FileUploadResult {
     access_key: String,
+    /// Soft quotas the upload went past
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
 }
 
 impl FileUploadResult {
     fn new(access_key: String) -> Self {
-        Self { access_key }
+        Self {
+            access_key,
+            warnings: Vec::new(),
+        }
     }
 }
 
-#[derive(Deserialize)]
-pub struct BucketUploadQuery {
-    auth: Option<String>,
+/// Detect the content type of an upload from the start of its content, `complete` is whether that is all of it
+/// Uploads without a declared content type are given the detected one, and checked against the bucket's policy.
+/// Uploads with one are flagged if it disagrees with the content
+fn sniff_content_type(
+    meta: &mut BlobMetadata,
+    declared: bool,
+    head: &[u8],
+    file_name: &str,
+    complete: bool,
+    policy: &UploadPolicy,
+) -> Result<(), PolicyViolation> {
+    // Blobs are often stored under names without an extension, the uploaded file's name may still have one
+    let file_name = match &meta.original_filename {
+        Some(original) if sniff::from_extension(file_name).is_none() => original.as_str(),
+        _ => file_name,
+    };
+    let detected = sniff::detect(head, file_name, complete);
+    meta.detected_content_type = Some(detected.to_string());
+
+    if !declared {
+        meta.content_type = detected.to_string();
+        meta.content_type_detected = true;
+        if !policy.allows_content_type(detected) {
+            return Err(PolicyViolation::ContentType(detected.to_string()));
+        }
+        return Ok(());
+    }
+
+    if sniff::is_mismatch(&meta.content_type, head) {
+        tracing::warn!(
+            "Declared content type {} doesn't match content, detected {}",
+            meta.content_type,
+            detected
+        );
+        meta.content_type_mismatch = true;
+        if policy.reject_content_type_mismatch {
+            return Err(PolicyViolation::ContentTypeMismatch);
+        }
+    }
+    Ok(())
+}
+
+fn policy_violation_response(violation: &PolicyViolation) -> HttpResponse {
+    let mut response = match violation {
+        PolicyViolation::ContentType(_)
+        | PolicyViolation::ContentTypeMismatch
+        | PolicyViolation::Extension => HttpResponse::UnsupportedMediaType(),
+        PolicyViolation::MissingHeader(_) => HttpResponse::BadRequest(),
+        PolicyViolation::TooLarge(_) => HttpResponse::PayloadTooLarge(),
+    };
+    response.body(violation.to_string())
+}
+
+/// Parse an optional RFC 3339 date from a header
+fn parse_date_header(req: &HttpRequest, name: &str) -> Result<Option<DateTime<Utc>>, ()> {
+    match req.headers().get(name) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|d| Some(d.with_timezone(&Utc)))
+            .ok_or(()),
+        None => Ok(None),
+    }
+}
+
+/// Parse a comma separated list of tags
+fn parse_tags(tags: &str) -> BTreeSet<String> {
+    tags.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
 }
 
 #[put("/api/bucket/{bucket_name}/{file_name}/upload")]
+#[allow(clippy::too_many_arguments)]
 pub async fn put_bucket_upload(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
     metadata: Data<MetadataManager>,
     file: WebPath<FileLocation>,
     mut data: Multipart,
     req: HttpRequest,
-    auth: Query<BucketUploadQuery>,
+    presign: Query<PresignQuery>,
     settings: Data<AppSettings>,
+    fulltext: Data<FullTextIndex>,
 ) -> Result<HttpResponse, AWError> {
     let _span = tracing::info_span!("bucket_upload").entered();
 
+    // Uploads through a presigned URL have to meet the URL's constraints
+    let presigned = presign.verify(
+        &settings,
+        PresignMethod::Put,
+        paths.tenant(),
+        &file.bucket_name,
+        &file.file_name,
+    );
+
     let bucket = match paths.get_bucket(Path::new(&file.bucket_name)) {
         Some(b) => b,
         None => return Ok(HttpResponse::InternalServerError().body("Failed to find bucket")),
     };
 
+    // Uploading needs upload permission, whether or not it replaces a soft-deleted file
+    let allowed = Authorizer::new(&req, &settings, &metadata)
+        .allow_presigned(PresignMethod::Put)
+        .can_file(
+            &bucket,
+            &file.bucket_name,
+            &file.file_name,
+            None,
+            Permission::Upload,
+        );
+
+    if !allowed {
+        warn!("Not allowed to upload");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
     // Trying to create a file that exists will fail even if it is deleted, so we do an early check here
     // If the file has been soft-deleted here then something else is being re-uploaded over it so we will remove it so the `create_bucket_file`
     // below won't fail.
@@ -201,43 +674,27 @@ pub async fn put_bucket_upload(
     // TODO: consider having a deleted bucket and when a file is soft deleted move it to a unique path in that bucket and track this history in the metadata
 
     if let Some(p) = paths.get_bucket_file(&bucket, Path::new(&file.file_name)) {
-        if let Ok(meta) = metadata.get_metadata(&p, true) {
-            if meta.deletion_date.is_some() {
+        // Only remove the metadata if it is still deleted, so we can't race with anything else touching this blob
+        match metadata.remove_deleted_metadata(&p) {
+            Ok(true) => {
                 tracing::warn!(
                     "Removing file {} as it it being overwritten, use unique paths to avoid this for now",
                     p.deref().display()
                 );
                 std::fs::remove_file(p.deref())?;
-                match metadata.remove_metadata(&p) {
-                    Ok(_) => {}
-                    Err(_e) => {
-                        tracing::warn!("Failed to remove metadata for {}", p.deref().display());
-                        return Ok(HttpResponse::InternalServerError()
-                            .body("Failed to create file, already exists"));
-                    }
-                }
-            } else {
+                fulltext::update_blob(&fulltext, p.to_path_buf(), false).await;
+            }
+            Ok(false) => {
                 tracing::warn!(
                     "Attempt to upload {} over existing file, delete it first",
                     p.deref().display()
                 );
             }
-        } else {
-            tracing::warn!("File exists but has no metadata??? {}", p.deref().display());
-            return Ok(
-                HttpResponse::InternalServerError().body("Failed to create file, already exists")
-            );
-        }
-    } else {
-        // New files must have an upload key which is correct, existing files are checked by anti-overwrite, delete requires blob auth
-        if let Some(auth) = &auth.auth {
-            if *auth != settings.bucket_upload_key {
-                warn!("Invalid bucket upload key");
-                return Ok(HttpResponse::BadRequest().body("Auth"));
+            Err(_e) => {
+                tracing::warn!("File exists but has no metadata??? {}", p.deref().display());
+                return Ok(HttpResponse::InternalServerError()
+                    .body("Failed to create file, already exists"));
             }
-        } else {
-            warn!("Missing auth key");
-            return Ok(HttpResponse::BadRequest().body("Auth"));
         }
     }
 
@@ -250,48 +707,284 @@ pub async fn put_bucket_upload(
         }
     };
 
-    let mut file = tokio::fs::File::create(path.deref()).await?;
     let mut meta = BlobMetadata::default();
 
+    // Without a declared content type, it's detected from the start of the content
+    let declared_type = req.headers().get("X-Blob-Content-Type").is_some();
     if let Some(ct) = req.headers().get("X-Blob-Content-Type") {
         meta.content_type = ct.to_str().expect("content type str").to_string();
     }
 
-    // Static access key
-    if let Some(ct) = req.headers().get("X-Blob-Access-Key") {
-        meta.access_key = ct.to_str().expect("Access key").to_string();
+    // Refuse uploads to full buckets before writing anything, the size is checked as the upload is streamed
+    let allowance = match quota::upload_allowance(&metadata, &paths, &bucket) {
+        Ok(Ok(a)) => a,
+        Ok(Err(exceeded)) => {
+            tracing::warn!("{} for {}", exceeded, &file.bucket_name);
+            return Ok(HttpResponse::InsufficientStorage().body(exceeded.to_string()));
+        }
+        Err(e) => {
+            tracing::warn!("Failed to check quota {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let max_size = if presigned {
+        if let Some(ct) = &presign.content_type
+            && *ct != meta.content_type
+        {
+            return Ok(HttpResponse::BadRequest().body("Content type not allowed by presigned URL"));
+        }
+        presign.max_size
+    } else {
+        None
+    };
+
+    // Static access key, a chosen one has to be as hard to guess as a generated one
+    let access_key = match req.headers().get("X-Blob-Access-Key") {
+        Some(key) => match key.to_str() {
+            Ok(key) if is_strong_key(key) => key.to_string(),
+            _ => {
+                return Ok(HttpResponse::BadRequest().body(format!(
+                    "Access key must be at least {} varied characters",
+                    MIN_CHOSEN_KEY_LEN
+                )));
+            }
+        },
+        None => generate_access_key(),
+    };
+    meta.set_access_key(&access_key);
+
+    if let Some(visibility) = req.headers().get(VISIBILITY_HEADER) {
+        match visibility.to_str().ok().and_then(Visibility::parse) {
+            Some(v) => meta.visibility = Some(v),
+            None => return Ok(HttpResponse::BadRequest().body("Invalid visibility")),
+        }
+    }
+
+    if let Some(disposition) = req.headers().get(DISPOSITION_HEADER) {
+        match disposition.to_str().ok().and_then(Disposition::parse) {
+            Some(d) => meta.disposition = Some(d),
+            None => return Ok(HttpResponse::BadRequest().body("Invalid disposition")),
+        }
+    }
+
+    match ResponseHeaders::from_upload(req.headers()) {
+        Ok(headers) => meta.response_headers = headers,
+        Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+    }
+
+    // Burn after reading
+    if let Some(max) = req.headers().get("X-Blob-Max-Downloads") {
+        match max.to_str().ok().and_then(|m| m.parse::<u32>().ok()) {
+            Some(max) if max > 0 => meta.max_downloads = Some(max),
+            _ => return Ok(HttpResponse::BadRequest().body("Invalid max downloads")),
+        }
+    }
+
+    // Embargo until the given time
+    match parse_date_header(&req, "X-Blob-Publish-At") {
+        Ok(publish_at) => meta.publish_at = publish_at,
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid publish date")),
+    }
+
+    // Custom metadata and tags
+    for (name, value) in req.headers() {
+        if let Some(key) = name.as_str().strip_prefix(USER_METADATA_HEADER_PREFIX) {
+            match value.to_str() {
+                Ok(value) => {
+                    meta.user_metadata
+                        .insert(key.to_string(), value.to_string());
+                }
+                Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid metadata value")),
+            }
+        }
+    }
+
+    if let Some(tags) = req.headers().get(TAGS_HEADER) {
+        match tags.to_str() {
+            Ok(tags) => meta.tags = parse_tags(tags),
+            Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid tags")),
+        }
+    }
+
+    if let Err(e) = validate_user_metadata(&meta.user_metadata, &meta.tags) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    let policy = match metadata.get_bucket_config(&bucket) {
+        Ok(config) => config.map(|c| c.upload_policy).unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!(
+                "Failed to get config for bucket {}: {}",
+                &file.bucket_name,
+                e
+            );
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let declared = declared_type.then_some(meta.content_type.as_str());
+    if let Err(violation) = policy.check_upload(req.headers(), &file.file_name, declared) {
+        tracing::warn!("Upload breaks bucket policy: {}", violation);
+        return Ok(policy_violation_response(&violation));
+    }
+
+    tracing::info!("Headers = {}", redact_headers(req.headers()));
+
+    let file_name = file.file_name.clone();
+
+    // Fail rather than truncate if another upload created this file since we checked
+    let mut file = match tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path.deref())
+        .await
+    {
+        Ok(f) => f,
+        Err(_e) => {
+            return Ok(
+                HttpResponse::InternalServerError().body("Failed to create file, already exists")
+            );
+        }
     };
 
-    tracing::info!("Headers = {:?}", req.headers());
+    let mut sha1 = Sha1::new();
+    let mut sha256 = Sha256::new();
+    let mut size = 0u64;
+    let mut head: Vec<u8> = Vec::with_capacity(SNIFF_LEN);
+    let mut sniffed = false;
 
     while let Some(item) = data.next().await {
         let mut field = item?;
 
+        // Downloads are named after the file that was uploaded
+        if meta.original_filename.is_none() {
+            meta.original_filename = field
+                .content_disposition()
+                .and_then(|cd| cd.get_filename())
+                .and_then(disposition::sanitize_filename);
+        }
+
         while let Some(chunk) = field.next().await {
             let data = chunk?;
+            sha1.update(&data);
+            sha256.update(&data);
+            size += data.len() as u64;
+            if max_size.is_some_and(|max| size > max) {
+                tracing::warn!("Upload larger than presigned URL allows");
+                drop(file);
+                tokio::fs::remove_file(path.deref()).await?;
+                return Ok(HttpResponse::PayloadTooLarge().finish());
+            }
+            if let Err(violation) = policy.check_size(size) {
+                tracing::warn!("Upload breaks bucket policy: {}", violation);
+                drop(file);
+                tokio::fs::remove_file(path.deref()).await?;
+                return Ok(policy_violation_response(&violation));
+            }
+            if allowance.max_bytes.is_some_and(|max| size > max) {
+                tracing::warn!("Upload larger than quota allows");
+                drop(file);
+                tokio::fs::remove_file(path.deref()).await?;
+                return Ok(HttpResponse::InsufficientStorage().body("Storage quota exceeded"));
+            }
+
+            if !sniffed {
+                head.extend_from_slice(&data[..data.len().min(SNIFF_LEN - head.len())]);
+                if head.len() == SNIFF_LEN {
+                    sniffed = true;
+                    if let Err(violation) = sniff_content_type(
+                        &mut meta,
+                        declared_type,
+                        &head,
+                        &file_name,
+                        false,
+                        &policy,
+                    ) {
+                        tracing::warn!("Upload breaks bucket policy: {}", violation);
+                        drop(file);
+                        tokio::fs::remove_file(path.deref()).await?;
+                        return Ok(policy_violation_response(&violation));
+                    }
+                }
+            }
+
             // filesystem operations are blocking, we have to use threadpool
             file.write_all(&data).await?;
         }
     }
 
-    match metadata.create_metadata(&path, &meta) {
-        Ok(_) => {}
+    // Uploads shorter than the sniffed length are only sniffed once they're complete
+    if !sniffed
+        && let Err(violation) =
+            sniff_content_type(&mut meta, declared_type, &head, &file_name, true, &policy)
+    {
+        tracing::warn!("Upload breaks bucket policy: {}", violation);
+        drop(file);
+        tokio::fs::remove_file(path.deref()).await?;
+        return Ok(policy_violation_response(&violation));
+    }
+
+    meta.checksums = Some(BlobChecksums {
+        sha1: format!("{:X}", sha1.finalize()),
+        sha256: format!("{:X}", sha256.finalize()),
+    });
+    meta.size = Some(size);
+
+    let mut res = FileUploadResult::new(access_key);
+
+    match metadata.create_metadata(&path, &meta, &allowance.quotas) {
+        Ok(Ok((bucket_usage, namespace_usage))) => {
+            for (quota, usage, scope) in [
+                (allowance.quotas.bucket, bucket_usage, QuotaScope::Bucket),
+                (
+                    allowance.quotas.namespace,
+                    namespace_usage,
+                    QuotaScope::Tenant,
+                ),
+            ] {
+                if quota.warns(usage) {
+                    tracing::warn!(
+                        "{} soft quota exceeded by {}",
+                        scope,
+                        path.deref().display()
+                    );
+                    res.warnings
+                        .push(format!("{} soft storage quota exceeded", scope));
+                }
+            }
+        }
+        Ok(Err(exceeded)) => {
+            tracing::warn!("{} by {}", exceeded, path.deref().display());
+            std::fs::remove_file(path.deref())?;
+            return Ok(HttpResponse::InsufficientStorage().body(exceeded.to_string()));
+        }
         Err(_e) => {
             std::fs::remove_file(path.deref())?;
             return Ok(HttpResponse::InternalServerError().finish());
         }
     }
-    let res = FileUploadResult::new(meta.access_key);
 
+    if fulltext::should_index(&meta) {
+        fulltext::update_blob(&fulltext, path.to_path_buf(), true).await;
+    }
     Ok(HttpResponse::Ok().json(&res))
 }
 
+/// Reasons a delete can be refused
+enum DeleteError {
+    AlreadyDeleted,
+    InvalidKey,
+}
+
 #[delete("/api/bucket/{bucket_name}/{file_name}/delete")]
 pub async fn delete_bucket_remove(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
     metadata: Data<MetadataManager>,
     file: WebPath<FileLocation>,
     req: HttpRequest,
+    fulltext: Data<FullTextIndex>,
+    settings: Data<AppSettings>,
 ) -> Result<HttpResponse, AWError> {
     let _span = tracing::info_span!("bucket_delete").entered();
 
@@ -311,45 +1004,283 @@ pub async fn delete_bucket_remove(
         }
     };
 
-    let mut meta = match metadata.get_metadata(&path, false) {
-        Ok(b) => b,
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+
+    // Credentials other than the blob's key are checked up front, sled can't be read inside the transaction
+    let can_delete = authorizer.can_file(
+        &bucket,
+        &file.bucket_name,
+        &file.file_name,
+        None,
+        Permission::Delete,
+    );
+
+    let res = metadata.update_metadata(&path, |meta| {
+        if meta.deletion_date.is_some() {
+            return Err(DeleteError::AlreadyDeleted);
+        }
+
+        // Checked against the metadata being updated, so a key rotated at the same time can't be used
+        if !can_delete && !authorizer.has_blob_key(meta, Permission::Delete) {
+            tracing::info!("Not allowed to delete");
+            return Err(DeleteError::InvalidKey);
+        }
+
+        meta.deletion_date = Some(Utc::now());
+        Ok(())
+    });
+
+    match res {
+        Ok(Ok(())) => fulltext::update_blob(&fulltext, path.to_path_buf(), false).await,
+        Ok(Err(DeleteError::AlreadyDeleted)) => {
+            tracing::warn!("Already removed");
+            return Ok(HttpResponse::BadRequest().body("Already deleted"));
+        }
+        Ok(Err(DeleteError::InvalidKey)) => return Ok(HttpResponse::Unauthorized().finish()),
         Err(_e) => {
-            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            tracing::warn!("Failed to save file metadata {}", &path.deref().display());
             return Ok(HttpResponse::InternalServerError().finish());
         }
-    };
-
-    if meta.deletion_date.is_some() {
-        tracing::warn!("Already removed");
-        return Ok(HttpResponse::BadRequest().body("Already deleted"));
     }
 
-    // Get the given auth header
-    let access_key = match req.headers().get("X-Blob-Access-Key") {
-        Some(ct) => ct.to_str().expect("Access key").to_string(),
+    Ok(HttpResponse::Ok().body("Bucket deleted".to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct MetadataPatch {
+    /// Metadata entries to set, a `null` value removes the entry
+    #[serde(default)]
+    metadata: BTreeMap<String, Option<String>>,
+
+    /// Replaces all the tags of the blob when given
+    tags: Option<BTreeSet<String>>,
+
+    content_type: Option<String>,
+
+    /// Replace the access key with a new random one, which is returned in the response
+    #[serde(default)]
+    rotate_access_key: bool,
+
+    /// For the following, a missing field leaves the value unchanged and `null` clears it
+    #[serde(default, deserialize_with = "double_option")]
+    publish_at: Option<Option<DateTime<Utc>>>,
+
+    #[serde(default, deserialize_with = "double_option")]
+    expires_at: Option<Option<DateTime<Utc>>>,
+
+    #[serde(default, deserialize_with = "double_option")]
+    max_downloads: Option<Option<u32>>,
+
+    /// `null` makes the blob use the bucket's default visibility
+    #[serde(default, deserialize_with = "double_option")]
+    visibility: Option<Option<Visibility>>,
+
+    /// `null` makes downloads of the blob inline
+    #[serde(default, deserialize_with = "double_option")]
+    disposition: Option<Option<Disposition>>,
+
+    /// Replaces the headers sent with downloads of the blob
+    response_headers: Option<ResponseHeaders>,
+}
+
+/// Deserialise a field that can be missing, null or a value
+pub fn double_option<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Serialize)]
+pub struct MetadataPatchResult {
+    metadata: BTreeMap<String, String>,
+    tags: BTreeSet<String>,
+    content_type: String,
+    publish_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<u32>,
+    visibility: Option<Visibility>,
+    disposition: Option<Disposition>,
+    response_headers: ResponseHeaders,
+    /// The new access key, only present if it was rotated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    access_key: Option<String>,
+}
+
+/// Reasons a metadata patch can be refused
+enum PatchError {
+    Deleted,
+    InvalidKey,
+    Invalid(String),
+}
+
+#[patch("/api/bucket/{bucket_name}/{file_name}/metadata")]
+pub async fn patch_bucket_metadata(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    file: WebPath<FileLocation>,
+    req: HttpRequest,
+    body: Json<MetadataPatch>,
+    fulltext: Data<FullTextIndex>,
+    settings: Data<AppSettings>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_patch_metadata").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.bucket_name)) {
+        Some(b) => b,
         None => {
-            tracing::warn!("No access key");
-            return Ok(HttpResponse::Unauthorized().finish());
+            tracing::warn!("Failed to find bucket {}", &file.bucket_name);
+            return Ok(HttpResponse::NotFound().finish());
         }
     };
 
-    if access_key != meta.access_key {
-        tracing::info!(
-            "Access key, got {}, expected {}",
-            access_key,
-            meta.access_key
-        );
-        return Ok(HttpResponse::Unauthorized().finish());
+    let path = match paths.get_bucket_file(&bucket, Path::new(&file.file_name)) {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Failed to find bucket file {}", &file.file_name);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+
+    if let Some(ct) = &body.content_type
+        && (ct.is_empty() || HeaderValue::from_str(ct).is_err())
+    {
+        return Ok(HttpResponse::BadRequest().body("Invalid content type"));
     }
 
-    meta.deletion_date = Some(Utc::now());
-    match metadata.save_metadata(&path, &meta) {
-        Ok(_) => {}
+    // A blob can't be given a content type its bucket wouldn't accept on upload
+    if let Some(ct) = &body.content_type {
+        match metadata.get_bucket_config(&bucket) {
+            Ok(config) => {
+                if !config.is_none_or(|c| c.upload_policy.allows_content_type(ct)) {
+                    let violation = PolicyViolation::ContentType(ct.clone());
+                    return Ok(policy_violation_response(&violation));
+                }
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to get config for bucket {}: {}",
+                    &file.bucket_name,
+                    e
+                );
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+
+    if let Some(Err(e)) = body
+        .response_headers
+        .as_ref()
+        .map(ResponseHeaders::validate)
+    {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    if body.max_downloads == Some(Some(0)) {
+        return Ok(HttpResponse::BadRequest().body("Invalid max downloads"));
+    }
+
+    // Generated up front as the update can be retried
+    let new_access_key = body.rotate_access_key.then(generate_access_key);
+
+    // Credentials other than the blob's key are checked up front, sled can't be read inside the transaction
+    let can_modify = authorizer.can_file(
+        &bucket,
+        &file.bucket_name,
+        &file.file_name,
+        None,
+        Permission::Modify,
+    );
+
+    let res = metadata.update_metadata(&path, |meta| {
+        if meta.deletion_date.is_some() {
+            return Err(PatchError::Deleted);
+        }
+
+        if !can_modify && !authorizer.has_blob_key(meta, Permission::Modify) {
+            return Err(PatchError::InvalidKey);
+        }
+
+        if let Some(ct) = &body.content_type {
+            meta.content_type = ct.clone();
+            meta.content_type_detected = false;
+        }
+
+        if let Some(key) = &new_access_key {
+            meta.set_access_key(key);
+        }
+
+        if let Some(publish_at) = body.publish_at {
+            meta.publish_at = publish_at;
+        }
+
+        if let Some(expires_at) = body.expires_at {
+            meta.expires_at = expires_at;
+        }
+
+        if let Some(max_downloads) = body.max_downloads {
+            meta.max_downloads = max_downloads;
+        }
+
+        if let Some(visibility) = body.visibility {
+            meta.visibility = visibility;
+        }
+
+        if let Some(disposition) = body.disposition {
+            meta.disposition = disposition;
+        }
+
+        if let Some(headers) = &body.response_headers {
+            meta.response_headers = headers.clone();
+        }
+
+        for (key, value) in &body.metadata {
+            let key = key.to_ascii_lowercase();
+            match value {
+                Some(value) => meta.user_metadata.insert(key, value.clone()),
+                None => meta.user_metadata.remove(&key),
+            };
+        }
+
+        if let Some(tags) = &body.tags {
+            meta.tags = tags.clone();
+        }
+
+        validate_user_metadata(&meta.user_metadata, &meta.tags).map_err(PatchError::Invalid)?;
+
+        Ok((
+            MetadataPatchResult {
+                metadata: meta.user_metadata.clone(),
+                tags: meta.tags.clone(),
+                content_type: meta.content_type.clone(),
+                publish_at: meta.publish_at,
+                expires_at: meta.expires_at,
+                max_downloads: meta.max_downloads,
+                visibility: meta.visibility,
+                disposition: meta.disposition,
+                response_headers: meta.response_headers.clone(),
+                access_key: new_access_key.clone(),
+            },
+            fulltext::should_index(meta),
+        ))
+    });
+
+    match res {
+        Ok(Ok((result, should_index))) => {
+            // Changing the content type can change if the blob should be in the full-text index
+            if body.content_type.is_some() {
+                fulltext::update_blob(&fulltext, path.to_path_buf(), should_index).await;
+            }
+
+            Ok(HttpResponse::Ok().json(result))
+        }
+        Ok(Err(PatchError::Deleted)) => Ok(HttpResponse::NotFound().finish()),
+        Ok(Err(PatchError::InvalidKey)) => Ok(HttpResponse::Unauthorized().finish()),
+        Ok(Err(PatchError::Invalid(e))) => Ok(HttpResponse::BadRequest().body(e)),
         Err(_e) => {
             tracing::warn!("Failed to save file metadata {}", &path.deref().display());
-            return Ok(HttpResponse::InternalServerError().finish());
+            Ok(HttpResponse::InternalServerError().finish())
         }
     }
-
-    Ok(HttpResponse::Ok().body("Bucket deleted".to_string()))
 }
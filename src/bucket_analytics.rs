@@ -0,0 +1,354 @@
+use crate::analytics::DailyStats;
+use crate::auth::{Authorizer, Permission};
+use crate::bucket::BucketLocation;
+use crate::file_location::FileLocation;
+use crate::metadata::MetadataManager;
+use crate::settings::AppSettings;
+use crate::tenant::TenantPaths;
+use crate::visibility;
+use actix_web::get;
+use actix_web::web::{Data, Path as WebPath, Query};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use chrono::{Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// How many days are reported if no range is given
+const DEFAULT_RANGE_DAYS: u64 = 30;
+
+/// How many blobs are in the top downloads report if no limit is given
+const DEFAULT_TOP_LIMIT: usize = 10;
+
+#[derive(Deserialize)]
+pub struct AnalyticsQuery {
+    /// First day to include, defaults to [DEFAULT_RANGE_DAYS] ago
+    from: Option<NaiveDate>,
+    /// Last day to include, defaults to today
+    to: Option<NaiveDate>,
+    /// Max number of blobs or buckets to return, only used by the top downloads and egress reports
+    limit: Option<usize>,
+}
+
+impl AnalyticsQuery {
+    fn range(&self) -> (NaiveDate, NaiveDate) {
+        let to = self.to.unwrap_or_else(|| Utc::now().date_naive());
+        let from = self.from.unwrap_or_else(|| {
+            to.checked_sub_days(Days::new(DEFAULT_RANGE_DAYS))
+                .unwrap_or(to)
+        });
+        (from, to)
+    }
+}
+
+#[derive(Serialize)]
+pub struct DayStats {
+    date: NaiveDate,
+    #[serde(flatten)]
+    stats: DailyStats,
+}
+
+#[derive(Serialize)]
+pub struct TimeSeries {
+    from: NaiveDate,
+    to: NaiveDate,
+    days: Vec<DayStats>,
+}
+
+#[derive(Serialize)]
+pub struct BlobStats {
+    blob_name: String,
+    #[serde(flatten)]
+    stats: DailyStats,
+}
+
+/// Drop the stats of private blobs, which look like they don't exist to anyone without a read credential
+fn without_private(
+    paths: &TenantPaths,
+    metadata: &MetadataManager,
+    stats: Vec<(Vec<u8>, NaiveDate, DailyStats)>,
+) -> Vec<(Vec<u8>, NaiveDate, DailyStats)> {
+    let mut private: HashMap<Vec<u8>, bool> = HashMap::new();
+    stats
+        .into_iter()
+        .filter(|(blob, _, _)| {
+            !*private
+                .entry(blob.clone())
+                .or_insert_with(|| visibility::is_private_blob(paths, metadata, blob))
+        })
+        .collect()
+}
+
+/// Sum stats for the same day, sorted by date
+fn to_time_series(
+    from: NaiveDate,
+    to: NaiveDate,
+    stats: Vec<(Vec<u8>, NaiveDate, DailyStats)>,
+) -> TimeSeries {
+    let mut days: BTreeMap<NaiveDate, DailyStats> = BTreeMap::new();
+    for (_, day, s) in stats {
+        days.entry(day).or_default().add(s);
+    }
+
+    TimeSeries {
+        from,
+        to,
+        days: days
+            .into_iter()
+            .map(|(date, stats)| DayStats { date, stats })
+            .collect(),
+    }
+}
+
+#[get("/api/bucket/{bucket_name}/{file_name}/analytics")]
+pub async fn get_blob_analytics(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<FileLocation>,
+    query: Query<AnalyticsQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("blob_analytics").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.bucket_name)) {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Failed to find bucket {}", &file.bucket_name);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let path = match paths.get_bucket_file(&bucket, Path::new(&file.file_name)) {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Failed to find bucket file {}", &file.file_name);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    // Private blobs look like they don't exist without a read credential
+    let meta = metadata.get_metadata(&path, false).ok();
+    let is_private = match &meta {
+        Some(meta) => visibility::is_private(&metadata, &bucket, meta).unwrap_or(true),
+        None => true,
+    };
+    if is_private
+        && !Authorizer::new(&req, &settings, &metadata).can_file(
+            &bucket,
+            &file.bucket_name,
+            &file.file_name,
+            meta.as_ref(),
+            Permission::Read,
+        )
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let (from, to) = query.range();
+
+    // Keys are `path\0date`, so include the separator to avoid matching other blobs that share a prefix
+    let mut prefix = path.as_os_str().as_bytes().to_vec();
+    prefix.push(0);
+
+    match metadata.get_daily_stats(&prefix, from, to) {
+        Ok(stats) => Ok(HttpResponse::Ok().json(to_time_series(from, to, stats))),
+        Err(e) => {
+            tracing::warn!("Failed to get stats for {}: {}", path.deref().display(), e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[get("/api/bucket/{name}/analytics")]
+pub async fn get_bucket_analytics(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    query: Query<AnalyticsQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_analytics").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let (from, to) = query.range();
+
+    let mut prefix = bucket.as_os_str().as_bytes().to_vec();
+    prefix.push(b'/');
+
+    let can_read =
+        Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Read);
+
+    match metadata.get_daily_stats(&prefix, from, to) {
+        Ok(stats) if can_read => Ok(HttpResponse::Ok().json(to_time_series(from, to, stats))),
+        Ok(stats) => Ok(HttpResponse::Ok().json(to_time_series(
+            from,
+            to,
+            without_private(&paths, &metadata, stats),
+        ))),
+        Err(e) => {
+            tracing::warn!("Failed to get stats for bucket {}: {}", &file.name, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[get("/api/bucket/{name}/analytics/top")]
+pub async fn get_bucket_top_downloads(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    query: Query<AnalyticsQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_top_downloads").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let (from, to) = query.range();
+
+    let mut prefix = bucket.as_os_str().as_bytes().to_vec();
+    prefix.push(b'/');
+
+    let can_read =
+        Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Read);
+
+    let stats = match metadata.get_daily_stats(&prefix, from, to) {
+        Ok(s) if can_read => s,
+        Ok(s) => without_private(&paths, &metadata, s),
+        Err(e) => {
+            tracing::warn!("Failed to get stats for bucket {}: {}", &file.name, e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let mut blobs: BTreeMap<Vec<u8>, DailyStats> = BTreeMap::new();
+    for (blob, _, s) in stats {
+        blobs.entry(blob).or_default().add(s);
+    }
+
+    let mut top: Vec<BlobStats> = blobs
+        .into_iter()
+        .filter_map(|(blob, stats)| {
+            let blob_path = Path::new(OsStr::from_bytes(&blob));
+            let blob_name = blob_path.strip_prefix(&*bucket).ok()?;
+            Some(BlobStats {
+                blob_name: blob_name.to_string_lossy().to_string(),
+                stats,
+            })
+        })
+        .collect();
+
+    top.sort_by(|a, b| b.stats.downloads.cmp(&a.stats.downloads));
+    top.truncate(query.limit.unwrap_or(DEFAULT_TOP_LIMIT));
+
+    Ok(HttpResponse::Ok().json(top))
+}
+
+/// Bytes sent by downloads from a bucket each day, needs admin permission on the bucket
+#[get("/api/bucket/{name}/egress")]
+pub async fn get_bucket_egress(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    query: Query<AnalyticsQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_egress").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let (from, to) = query.range();
+
+    // Keys are `path\0date`, so include the separator to avoid matching other buckets that share a prefix
+    let mut prefix = bucket.as_os_str().as_bytes().to_vec();
+    prefix.push(0);
+
+    match metadata.get_egress(&prefix, from, to) {
+        Ok(stats) => Ok(HttpResponse::Ok().json(to_time_series(from, to, stats))),
+        Err(e) => {
+            tracing::warn!("Failed to get egress of bucket {}: {}", &file.name, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct BucketEgress {
+    bucket_name: String,
+    #[serde(flatten)]
+    stats: DailyStats,
+}
+
+/// Bytes sent by downloads from every bucket of the request's namespace, most first. Needs an admin of the namespace
+#[get("/api/egress")]
+pub async fn get_egress(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    query: Query<AnalyticsQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("egress").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_namespace_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let (from, to) = query.range();
+
+    let stats = match metadata.get_egress(&paths.root_key_prefix(), from, to) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!("Failed to get egress {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    // The default namespace's root also holds every tenant's buckets, which aren't its own
+    let root = paths.get_root();
+    let mut buckets: BTreeMap<String, DailyStats> = BTreeMap::new();
+    for (bucket, _, s) in stats {
+        let bucket_path = Path::new(OsStr::from_bytes(&bucket));
+        if bucket_path.parent() != Some(root.as_path()) {
+            continue;
+        }
+        if let Some(name) = bucket_path.file_name() {
+            buckets
+                .entry(name.to_string_lossy().to_string())
+                .or_default()
+                .add(s);
+        }
+    }
+
+    let mut egress: Vec<BucketEgress> = buckets
+        .into_iter()
+        .map(|(bucket_name, stats)| BucketEgress { bucket_name, stats })
+        .collect();
+    egress.sort_by(|a, b| b.stats.bytes.cmp(&a.stats.bytes));
+    if let Some(limit) = query.limit {
+        egress.truncate(limit);
+    }
+
+    Ok(HttpResponse::Ok().json(egress))
+}
@@ -0,0 +1,118 @@
+use crate::access_key::{AccessKeyHash, constant_time_eq, generate_access_key};
+use crate::quota::Quota;
+use crate::response_headers::ResponseHeaders;
+use crate::settings::AppSettings;
+use crate::upload_policy::UploadPolicy;
+use crate::visibility::Visibility;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The kinds of per-bucket credentials
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum BucketKeyKind {
+    /// Can read private blobs
+    Read,
+    /// Can upload new blobs
+    Upload,
+    /// Can do anything to the bucket, including rotating its keys
+    Admin,
+}
+
+impl BucketKeyKind {
+    pub const ALL: [BucketKeyKind; 3] = [
+        BucketKeyKind::Read,
+        BucketKeyKind::Upload,
+        BucketKeyKind::Admin,
+    ];
+}
+
+/// Settings and credentials for a single bucket, stored in the metadata DB
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BucketConfig {
+    pub read_key: AccessKeyHash,
+    pub upload_key: AccessKeyHash,
+    pub admin_key: AccessKeyHash,
+
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+
+    /// Visibility of blobs that don't set their own
+    #[serde(default)]
+    pub visibility: Visibility,
+
+    /// Limits on what can be stored in the bucket, only changed by admins of the whole namespace
+    #[serde(default)]
+    pub quota: Quota,
+
+    #[serde(default)]
+    pub upload_policy: UploadPolicy,
+
+    /// Headers sent with downloads of blobs that don't set their own
+    #[serde(default)]
+    pub response_headers: ResponseHeaders,
+
+    /// Bytes per second shared by all downloads from the bucket, overriding the default, see [crate::bandwidth]
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+}
+
+impl BucketConfig {
+    /// Create the config for a new bucket, returning it along with the plaintext keys
+    pub fn new() -> (Self, Vec<(BucketKeyKind, String)>) {
+        let mut config = Self {
+            created_at: Some(Utc::now()),
+            ..Default::default()
+        };
+        let keys = BucketKeyKind::ALL
+            .iter()
+            .map(|kind| (*kind, config.rotate_key(*kind)))
+            .collect();
+        (config, keys)
+    }
+
+    /// Replace one of the keys of this bucket with a new random one, which is returned
+    pub fn rotate_key(&mut self, kind: BucketKeyKind) -> String {
+        let key = generate_access_key();
+        let hash = AccessKeyHash::new(&key);
+        match kind {
+            BucketKeyKind::Read => self.read_key = hash,
+            BucketKeyKind::Upload => self.upload_key = hash,
+            BucketKeyKind::Admin => self.admin_key = hash,
+        }
+        key
+    }
+
+    fn key_hash(&self, kind: BucketKeyKind) -> &AccessKeyHash {
+        match kind {
+            BucketKeyKind::Read => &self.read_key,
+            BucketKeyKind::Upload => &self.upload_key,
+            BucketKeyKind::Admin => &self.admin_key,
+        }
+    }
+}
+
+/// Check if `key` gives `kind` access to a bucket with the given config
+/// The global bucket creation key is a super-admin key for every bucket, and the global upload key can upload to any
+/// bucket. Buckets created by older versions have no config, so only the global keys work for them
+pub fn check_bucket_key(
+    settings: &AppSettings,
+    config: Option<&BucketConfig>,
+    key: &str,
+    kind: BucketKeyKind,
+) -> bool {
+    if constant_time_eq(key, &settings.bucket_creation_key) {
+        return true;
+    }
+
+    if kind == BucketKeyKind::Upload && constant_time_eq(key, &settings.bucket_upload_key) {
+        return true;
+    }
+
+    let Some(config) = config else {
+        return false;
+    };
+
+    config.admin_key.verify(key)
+        || (kind != BucketKeyKind::Admin && config.key_hash(kind).verify(key))
+}
@@ -1,21 +1,33 @@
+use crate::auth::{Authorizer, Permission};
+use crate::bandwidth::{self, BandwidthLimiter};
+use crate::disposition::{self, Disposition, DownloadQuery};
 use crate::file_location::FileLocation;
-use crate::metadata::MetadataManager;
-use crate::path::PathManager;
-use actix_web::get;
-use actix_web::http::header;
-use actix_web::web::Data;
-use actix_web::{Error as AWError, HttpResponse, web};
+use crate::metadata::{DownloadOutcome, MetadataManager, TAGS_HEADER, USER_METADATA_HEADER_PREFIX};
+use crate::path::{BlobPath, BucketPath, PathExists};
+use crate::presign::PresignMethod;
+use crate::settings::AppSettings;
+use crate::sniff;
+use crate::tenant::TenantPaths;
+use crate::visibility::{self, Visibility};
+use actix_web::http::{Method, header};
+use actix_web::route;
+use actix_web::web::{Data, Query};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse, web};
 use std::fs::File;
-use std::io::Read;
 use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use tracing::log;
 
-#[get("/{bucket_name}/{file_name}")]
+#[route("/{bucket_name}/{file_name}", method = "GET", method = "HEAD")]
 async fn get_file(
-    paths: Data<PathManager>,
+    paths: TenantPaths,
     metadata: Data<MetadataManager>,
+    bandwidth: Data<BandwidthLimiter>,
+    settings: Data<AppSettings>,
     file: web::Path<FileLocation>,
+    query: Query<DownloadQuery>,
+    req: HttpRequest,
 ) -> Result<HttpResponse, AWError> {
     let bucket = match paths.get_bucket(Path::new(&file.bucket_name)) {
         Some(b) => b,
@@ -33,7 +45,7 @@ async fn get_file(
         }
     };
 
-    let mut file_meta = match metadata.get_metadata(&path) {
+    let file_meta = match metadata.get_metadata(&path, false) {
         Ok(m) => m,
         Err(_e) => {
             tracing::warn!("Failed to find metadata {}", &path.deref().display());
@@ -41,27 +53,150 @@ async fn get_file(
         }
     };
 
-    file_meta.download_count += 1;
+    let is_private = match visibility::is_private(&metadata, &bucket, &file_meta) {
+        Ok(p) => p,
+        Err(e) => {
+            tracing::warn!("Failed to check visibility {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
 
-    if let Err(e) = metadata.save_metadata(&path, &file_meta) {
-        tracing::warn!("Failed to save metadata {}", e);
-    }
+    // Embargoed and private files pretend not to exist to anyone without a read credential
+    if file_meta.is_embargoed() || is_private {
+        let can_read = Authorizer::new(&req, &settings, &metadata)
+            .allow_presigned(PresignMethod::Get)
+            .can_file(
+                &bucket,
+                &file.bucket_name,
+                &file.file_name,
+                Some(&file_meta),
+                Permission::Read,
+            );
 
-    if file_meta.deletion_date.is_some() {
-        log::warn!("Attempt to access soft-deleted file");
-        return Ok(HttpResponse::NotFound().finish());
+        if !can_read {
+            tracing::warn!("Attempt to access embargoed or private file");
+            return Ok(HttpResponse::NotFound().finish());
+        }
     }
 
-    let file = File::open(path.deref());
+    // HEAD requests only check the blob is available, they don't count as a download
+    let head = req.method() == Method::HEAD;
+    let outcome = if head {
+        Ok(file_meta
+            .unavailable_reason()
+            .unwrap_or(DownloadOutcome::Allowed(Box::new(file_meta))))
+    } else {
+        metadata.record_download(&path)
+    };
 
-    if let Ok(mut file) = file {
-        let mut content: Vec<u8> = Vec::new();
-        file.read_to_end(&mut content)?;
+    download_blob(
+        &metadata,
+        &bandwidth,
+        &bucket,
+        &path,
+        outcome,
+        head,
+        query.is_download(),
+    )
+}
 
-        Ok(HttpResponse::Ok()
-            .append_header((header::CONTENT_TYPE, file_meta.content_type))
-            .body(content))
+/// Respond with the content of a blob that the request is allowed to see, given the outcome of counting its download
+/// `head` leaves out the content, and `download` makes it an attachment whatever the blob's own disposition
+pub fn download_blob(
+    metadata: &Data<MetadataManager>,
+    bandwidth: &Data<BandwidthLimiter>,
+    bucket: &BucketPath<PathExists>,
+    path: &BlobPath<PathExists>,
+    outcome: anyhow::Result<DownloadOutcome>,
+    head: bool,
+    download: bool,
+) -> Result<HttpResponse, AWError> {
+    let file_meta = match outcome {
+        Ok(DownloadOutcome::Allowed(m)) => *m,
+        Ok(DownloadOutcome::Deleted) => {
+            log::warn!("Attempt to access soft-deleted file");
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        Ok(DownloadOutcome::LimitReached) => {
+            log::warn!("Attempt to access file past its download limit");
+            return Ok(HttpResponse::Gone().finish());
+        }
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let config = match metadata.get_bucket_config(bucket) {
+        Ok(c) => c.unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!("Failed to get bucket config {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let mut response = HttpResponse::Ok();
+    response.append_header((header::CONTENT_TYPE, file_meta.content_type.as_str()));
+    // Browsers mustn't second guess the type either, or any blob could be turned into a page
+    response.append_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"));
+
+    // Caches would keep serving blobs that are private or can stop being available
+    let cacheable = file_meta.visibility.unwrap_or(config.visibility) == Visibility::Public
+        && file_meta.max_downloads.is_none()
+        && file_meta.publish_at.is_none();
+    file_meta
+        .response_headers
+        .or(&config.response_headers)
+        .apply(&mut response, cacheable);
+
+    // Markup that wasn't declared as such would run its scripts on this origin if shown inline
+    let kind = if download
+        || (file_meta.content_type_detected && sniff::is_active(&file_meta.content_type))
+    {
+        Disposition::Attachment
     } else {
-        Ok(HttpResponse::NotFound().finish())
+        file_meta.disposition.unwrap_or_default()
+    };
+    let filename = match &file_meta.original_filename {
+        Some(name) => name.clone(),
+        None => path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    response.insert_header(disposition::content_disposition(kind, &filename));
+
+    for (key, value) in &file_meta.user_metadata {
+        response.append_header((
+            format!("{}{}", USER_METADATA_HEADER_PREFIX, key),
+            value.as_str(),
+        ));
+    }
+
+    if !file_meta.tags.is_empty() {
+        let tags: Vec<&str> = file_meta.tags.iter().map(String::as_str).collect();
+        response.append_header((TAGS_HEADER, tags.join(",")));
+    }
+
+    if head {
+        return Ok(response.finish());
     }
+
+    let Ok(file) = File::open(path.deref()) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let size = file.metadata()?.len();
+
+    if let Err(e) = metadata.record_daily_download(path, size) {
+        tracing::warn!("Failed to record download stats {}", e);
+    }
+
+    Ok(response.body(bandwidth::throttled_body(
+        file,
+        size,
+        Data::clone(metadata),
+        Data::clone(bandwidth),
+        bucket.as_os_str().as_bytes().to_vec(),
+        config.bandwidth_limit,
+    )))
 }
@@ -0,0 +1,108 @@
+use crate::auth::{Authorizer, Permission};
+use crate::file_location::FileLocation;
+use crate::metadata::MetadataManager;
+use crate::presign::{PresignMethod, presign_path};
+use crate::settings::AppSettings;
+use crate::tenant::TenantPaths;
+use actix_web::post;
+use actix_web::web::{Data, Json, Path as WebPath};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// How long a presigned URL lasts if no expiry is given
+const DEFAULT_PRESIGN_LIFETIME_SECS: i64 = 15 * 60;
+
+/// Longest a presigned URL can last
+const MAX_PRESIGN_LIFETIME_SECS: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+    method: PresignMethod,
+    /// Seconds until the URL expires, defaults to 15 minutes
+    expires_in: Option<i64>,
+    /// Only allow uploads with this content type
+    content_type: Option<String>,
+    /// Only allow uploads up to this many bytes
+    max_size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct PresignResult {
+    url: String,
+    path: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Make a presigned URL for a blob, GET URLs need read permission on it, PUT URLs need upload permission
+#[post("/api/bucket/{bucket_name}/{file_name}/presign")]
+pub async fn post_bucket_presign(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<FileLocation>,
+    req: HttpRequest,
+    body: Json<PresignRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_presign").entered();
+
+    let Some(secret) = &settings.token_secret else {
+        return Ok(HttpResponse::NotImplemented().body("Presigned URLs are not enabled"));
+    };
+
+    let bucket = match paths.get_bucket(Path::new(&file.bucket_name)) {
+        Some(b) => b,
+        None => {
+            tracing::warn!("Failed to find bucket {}", &file.bucket_name);
+            return Ok(HttpResponse::NotFound().finish());
+        }
+    };
+
+    let permission = match body.method {
+        PresignMethod::Get => Permission::Read,
+        PresignMethod::Put => Permission::Upload,
+    };
+
+    let authorised = Authorizer::new(&req, &settings, &metadata).can_file(
+        &bucket,
+        &file.bucket_name,
+        &file.file_name,
+        None,
+        permission,
+    );
+
+    if !authorised {
+        tracing::warn!("Invalid key for presigning {:?}", body.method);
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let lifetime = body.expires_in.unwrap_or(DEFAULT_PRESIGN_LIFETIME_SECS);
+    if lifetime <= 0 || lifetime > MAX_PRESIGN_LIFETIME_SECS {
+        return Ok(HttpResponse::BadRequest().body("Invalid expiry"));
+    }
+
+    if body.method == PresignMethod::Get && (body.content_type.is_some() || body.max_size.is_some())
+    {
+        return Ok(HttpResponse::BadRequest().body("Constraints only apply to uploads"));
+    }
+
+    let expires_at = Utc::now() + Duration::seconds(lifetime);
+    let path = presign_path(
+        secret,
+        body.method,
+        paths.tenant(),
+        &file.bucket_name,
+        &file.file_name,
+        expires_at,
+        body.content_type.as_deref(),
+        body.max_size,
+    );
+
+    Ok(HttpResponse::Ok().json(PresignResult {
+        url: settings.public_url(&req, &path),
+        path,
+        // The URL only has second precision
+        expires_at: DateTime::from_timestamp(expires_at.timestamp(), 0).unwrap_or(expires_at),
+    }))
+}
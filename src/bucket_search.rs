@@ -0,0 +1,331 @@
+use crate::auth::{Authorizer, Permission};
+use crate::bucket::BucketLocation;
+use crate::index::SearchFilter;
+use crate::metadata::{BlobMetadata, MetadataManager};
+use crate::path::PathManager;
+use crate::settings::AppSettings;
+use crate::tenant::TenantPaths;
+use crate::visibility;
+use actix_web::get;
+use actix_web::web::{self, Data, Path as WebPath, Query};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// How many results are returned if no limit is given
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+/// Most results that can be returned by one search
+const MAX_SEARCH_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub struct SearchLimit {
+    limit: Option<usize>,
+}
+
+/// Which buckets a request can list, looked up once per bucket
+struct ListAccess<'a> {
+    paths: &'a PathManager,
+    authorizer: Authorizer<'a>,
+    buckets: RefCell<HashMap<String, bool>>,
+}
+
+impl<'a> ListAccess<'a> {
+    fn new(paths: &'a PathManager, authorizer: Authorizer<'a>) -> Self {
+        Self {
+            paths,
+            authorizer,
+            buckets: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn can_list(&self, bucket_name: &str) -> bool {
+        *self
+            .buckets
+            .borrow_mut()
+            .entry(bucket_name.to_string())
+            .or_insert_with(|| {
+                self.paths
+                    .get_bucket(Path::new(bucket_name))
+                    .is_some_and(|bucket| {
+                        self.authorizer.can(&bucket, bucket_name, Permission::List)
+                    })
+            })
+    }
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    bucket_name: String,
+    blob_name: String,
+    content_type: String,
+    size: Option<u64>,
+    created_at: Option<DateTime<Utc>>,
+    deletion_date: Option<DateTime<Utc>>,
+    embargoed: bool,
+    metadata: BTreeMap<String, String>,
+    tags: BTreeSet<String>,
+}
+
+fn search(
+    paths: &PathManager,
+    metadata: &MetadataManager,
+    access: &ListAccess,
+    prefix: &[u8],
+    filter: &SearchFilter,
+    limit: &SearchLimit,
+) -> HttpResponse {
+    let limit = limit
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+
+    // Private blobs look like they don't exist to anyone searching
+    // The default namespace's prefix also covers tenants' blobs, which can't be located from it
+    let visible = |key: &[u8], meta: &BlobMetadata| {
+        paths
+            .locate_blob(Path::new(OsStr::from_bytes(key)))
+            .is_some_and(|(bucket_name, _)| access.can_list(&bucket_name))
+            && !visibility::is_private_key(paths, metadata, key, meta)
+    };
+
+    let found = match metadata.search(prefix, filter, limit, visible) {
+        Ok(f) => f,
+        Err(e) => {
+            tracing::warn!("Failed to search metadata {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let results: Vec<SearchResult> = found
+        .into_iter()
+        .filter_map(|(key, meta)| {
+            let (bucket_name, blob_name) = paths.locate_blob(Path::new(OsStr::from_bytes(&key)))?;
+            Some(SearchResult {
+                bucket_name,
+                blob_name,
+                embargoed: meta.is_embargoed(),
+                content_type: meta.content_type,
+                size: meta.size,
+                created_at: meta.created_at,
+                deletion_date: meta.deletion_date,
+                metadata: meta.user_metadata,
+                tags: meta.tags,
+            })
+        })
+        .collect();
+
+    HttpResponse::Ok().json(results)
+}
+
+/// Search every bucket of the request's namespace that the request can list
+#[get("/api/search")]
+pub async fn get_search(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    filter: Query<SearchFilter>,
+    limit: Query<SearchLimit>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("search").entered();
+
+    let access = ListAccess::new(&paths, Authorizer::new(&req, &settings, &metadata));
+
+    Ok(search(
+        &paths,
+        &metadata,
+        &access,
+        &paths.root_key_prefix(),
+        &filter,
+        &limit,
+    ))
+}
+
+/// Search a bucket, needs list permission on it
+#[get("/api/bucket/{name}/search")]
+pub async fn get_bucket_search(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    filter: Query<SearchFilter>,
+    limit: Query<SearchLimit>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_search").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let access = ListAccess::new(&paths, Authorizer::new(&req, &settings, &metadata));
+    if !access.can_list(&file.name) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let mut prefix = bucket.as_os_str().as_bytes().to_vec();
+    prefix.push(b'/');
+
+    Ok(search(&paths, &metadata, &access, &prefix, &filter, &limit))
+}
+
+/// Register the full-text search endpoints, if the feature is enabled
+#[cfg_attr(not(feature = "full-text-search"), allow(unused_variables))]
+pub fn configure_text_search(cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "full-text-search")]
+    cfg.service(text::get_text_search)
+        .service(text::get_bucket_text_search);
+}
+
+#[cfg(feature = "full-text-search")]
+mod text {
+    use super::{DEFAULT_SEARCH_LIMIT, ListAccess, MAX_SEARCH_LIMIT};
+    use crate::auth::Authorizer;
+    use crate::bucket::BucketLocation;
+    use crate::fulltext::FullTextIndex;
+    use crate::metadata::MetadataManager;
+    use crate::path::PathManager;
+    use crate::settings::AppSettings;
+    use crate::tenant::TenantPaths;
+    use crate::visibility;
+    use actix_web::get;
+    use actix_web::web::{Data, Path as WebPath, Query};
+    use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+    use serde::{Deserialize, Serialize};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[derive(Deserialize)]
+    pub struct TextSearchQuery {
+        /// Query in tantivy query syntax
+        q: String,
+        limit: Option<usize>,
+    }
+
+    #[derive(Serialize)]
+    pub struct TextSearchResult {
+        bucket_name: String,
+        blob_name: String,
+        content_type: String,
+        /// HTML snippet of the matching content, matches are wrapped in `<b>`
+        snippet: String,
+    }
+
+    fn text_search(
+        paths: &PathManager,
+        metadata: &MetadataManager,
+        fulltext: &FullTextIndex,
+        access: &ListAccess,
+        prefix: &[u8],
+        query: &TextSearchQuery,
+    ) -> HttpResponse {
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .min(MAX_SEARCH_LIMIT);
+
+        // Only return blobs that could be downloaded by anyone, from buckets the request can list
+        // The default namespace's prefix also covers tenants' blobs, which can't be located from it
+        let visible = |key: &[u8]| {
+            key.starts_with(prefix)
+                && paths
+                    .locate_blob(Path::new(OsStr::from_bytes(key)))
+                    .is_some_and(|(bucket_name, _)| access.can_list(&bucket_name))
+                && metadata
+                    .get_metadata_by_key(key)
+                    .ok()
+                    .flatten()
+                    .is_some_and(|m| {
+                        m.unavailable_reason().is_none()
+                            && !m.is_embargoed()
+                            && !visibility::is_private_key(paths, metadata, key, &m)
+                    })
+        };
+
+        let found = match fulltext.search(&query.q, limit, visible) {
+            Ok(f) => f,
+            Err(e) => {
+                tracing::warn!("Full-text search failed {}", e);
+                return HttpResponse::BadRequest().body("Invalid query");
+            }
+        };
+
+        let results: Vec<TextSearchResult> = found
+            .into_iter()
+            .filter_map(|m| {
+                let meta = metadata.get_metadata_by_key(&m.blob_key).ok().flatten()?;
+                let (bucket_name, blob_name) =
+                    paths.locate_blob(Path::new(OsStr::from_bytes(&m.blob_key)))?;
+                Some(TextSearchResult {
+                    bucket_name,
+                    blob_name,
+                    content_type: meta.content_type,
+                    snippet: m.snippet,
+                })
+            })
+            .collect();
+
+        HttpResponse::Ok().json(results)
+    }
+
+    #[get("/api/search/text")]
+    pub async fn get_text_search(
+        paths: TenantPaths,
+        metadata: Data<MetadataManager>,
+        settings: Data<AppSettings>,
+        fulltext: Data<FullTextIndex>,
+        query: Query<TextSearchQuery>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, AWError> {
+        let _span = tracing::info_span!("text_search").entered();
+
+        let access = ListAccess::new(&paths, Authorizer::new(&req, &settings, &metadata));
+
+        Ok(text_search(
+            &paths,
+            &metadata,
+            &fulltext,
+            &access,
+            &paths.root_key_prefix(),
+            &query,
+        ))
+    }
+
+    #[get("/api/bucket/{name}/search/text")]
+    pub async fn get_bucket_text_search(
+        paths: TenantPaths,
+        metadata: Data<MetadataManager>,
+        settings: Data<AppSettings>,
+        fulltext: Data<FullTextIndex>,
+        file: WebPath<BucketLocation>,
+        query: Query<TextSearchQuery>,
+        req: HttpRequest,
+    ) -> Result<HttpResponse, AWError> {
+        let _span = tracing::info_span!("bucket_text_search").entered();
+
+        let bucket = match paths.get_bucket(Path::new(&file.name)) {
+            Some(b) => b,
+            None => return Ok(HttpResponse::NotFound().finish()),
+        };
+
+        let access = ListAccess::new(&paths, Authorizer::new(&req, &settings, &metadata));
+        if !access.can_list(&file.name) {
+            return Ok(HttpResponse::Unauthorized().finish());
+        }
+
+        let mut prefix = bucket.as_os_str().as_bytes().to_vec();
+        prefix.push(b'/');
+
+        Ok(text_search(
+            &paths, &metadata, &fulltext, &access, &prefix, &query,
+        ))
+    }
+}
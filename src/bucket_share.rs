@@ -0,0 +1,405 @@
+use crate::access_key::PasswordHash;
+use crate::auth::{Authorizer, Permission};
+use crate::bandwidth::BandwidthLimiter;
+use crate::bucket_get_file::download_blob;
+use crate::disposition::DownloadQuery;
+use crate::file_location::FileLocation;
+use crate::metadata::MetadataManager;
+use crate::path::PathManager;
+use crate::settings::AppSettings;
+use crate::share::{SHARE_PATH_PREFIX, ShareLink, ShareOutcome, generate_share_id, password_page};
+use crate::tenant::TenantPaths;
+use actix_web::http::header::ContentType;
+use actix_web::web::{Data, Form, Json, Path as WebPath, Query};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use actix_web::{delete, get, post};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// How long a share link lasts if no expiry is given
+const DEFAULT_SHARE_LIFETIME_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// Longest a share link can last
+const MAX_SHARE_LIFETIME_SECS: i64 = 365 * 24 * 60 * 60;
+
+#[derive(Serialize)]
+pub struct ShareDetails {
+    id: String,
+    url: String,
+    path: String,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    max_downloads: Option<u32>,
+    download_count: u32,
+    password_protected: bool,
+}
+
+impl ShareDetails {
+    fn new(share: ShareLink, settings: &AppSettings, req: &HttpRequest) -> Self {
+        let path = format!("{}{}", SHARE_PATH_PREFIX, share.id);
+        Self {
+            url: settings.public_url(req, &path),
+            path,
+            id: share.id,
+            created_at: share.created_at,
+            expires_at: share.expires_at,
+            max_downloads: share.max_downloads,
+            download_count: share.download_count,
+            password_protected: share.password_hash.is_some(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateShareRequest {
+    /// Seconds until the link expires, defaults to a week
+    expires_in: Option<i64>,
+    max_downloads: Option<u32>,
+    password: Option<String>,
+}
+
+#[post("/api/bucket/{bucket_name}/{file_name}/shares")]
+pub async fn post_bucket_share_create(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<FileLocation>,
+    req: HttpRequest,
+    body: Json<CreateShareRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("share_create").entered();
+
+    let Some(bucket) = paths.get_bucket(Path::new(&file.bucket_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let Some(path) = paths.get_bucket_file(&bucket, Path::new(&file.file_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let meta = match metadata.get_metadata(&path, false) {
+        Ok(m) => m,
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can_file(
+        &bucket,
+        &file.bucket_name,
+        &file.file_name,
+        Some(&meta),
+        Permission::Modify,
+    ) {
+        tracing::warn!("Not allowed to share blob");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if meta.deletion_date.is_some() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let lifetime = body.expires_in.unwrap_or(DEFAULT_SHARE_LIFETIME_SECS);
+    if lifetime <= 0 || lifetime > MAX_SHARE_LIFETIME_SECS {
+        return Ok(HttpResponse::BadRequest().body("Invalid expiry"));
+    }
+
+    if body.max_downloads == Some(0) {
+        return Ok(HttpResponse::BadRequest().body("Invalid max downloads"));
+    }
+
+    let password_hash = match body.password.as_deref() {
+        Some("") => return Ok(HttpResponse::BadRequest().body("Invalid password")),
+        Some(password) => match PasswordHash::new_blocking(password.to_string()).await {
+            Ok(h) => Some(h),
+            Err(e) => {
+                tracing::warn!("{}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+
+    let share = ShareLink {
+        id: generate_share_id(),
+        blob_key: path.as_os_str().as_bytes().to_vec(),
+        tenant: paths.tenant().map(str::to_string),
+        bucket_name: file.bucket_name.clone(),
+        file_name: file.file_name.clone(),
+        created_at: Utc::now(),
+        expires_at: Some(Utc::now() + Duration::seconds(lifetime)),
+        max_downloads: body.max_downloads,
+        download_count: 0,
+        password_hash,
+    };
+
+    match metadata.create_share(&share) {
+        Ok(()) => {
+            // Ids are as good as the link itself, so they stay out of the logs
+            tracing::info!("Created share of {}/{}", share.bucket_name, share.file_name);
+            Ok(HttpResponse::Ok().json(ShareDetails::new(share, &settings, &req)))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to create share {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[get("/api/bucket/{bucket_name}/{file_name}/shares")]
+pub async fn get_bucket_shares(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<FileLocation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("share_list").entered();
+
+    let Some(bucket) = paths.get_bucket(Path::new(&file.bucket_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let Some(path) = paths.get_bucket_file(&bucket, Path::new(&file.file_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let meta = match metadata.get_metadata(&path, false) {
+        Ok(m) => m,
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can_file(
+        &bucket,
+        &file.bucket_name,
+        &file.file_name,
+        Some(&meta),
+        Permission::Modify,
+    ) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match metadata.list_shares(&path) {
+        Ok(shares) => Ok(HttpResponse::Ok().json(
+            shares
+                .into_iter()
+                .filter(|share| share.is_for(&meta))
+                .map(|share| ShareDetails::new(share, &settings, &req))
+                .collect::<Vec<_>>(),
+        )),
+        Err(e) => {
+            tracing::warn!("Failed to list shares {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareLocation {
+    bucket_name: String,
+    file_name: String,
+    id: String,
+}
+
+#[delete("/api/bucket/{bucket_name}/{file_name}/shares/{id}")]
+pub async fn delete_bucket_share(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    share: WebPath<ShareLocation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("share_revoke").entered();
+
+    let Some(bucket) = paths.get_bucket(Path::new(&share.bucket_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let Some(path) = paths.get_bucket_file(&bucket, Path::new(&share.file_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let meta = match metadata.get_metadata(&path, false) {
+        Ok(m) => m,
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can_file(
+        &bucket,
+        &share.bucket_name,
+        &share.file_name,
+        Some(&meta),
+        Permission::Modify,
+    ) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match metadata.remove_share(&path, &share.id) {
+        Ok(true) => {
+            tracing::info!("Revoked share of {}/{}", share.bucket_name, share.file_name);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to revoke share {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareId {
+    id: String,
+}
+
+#[derive(Deserialize)]
+pub struct SharePasswordForm {
+    password: String,
+}
+
+/// Download a blob through a share link, counting it against the link's limit
+/// Share links work from any host, so the blob is found in the namespace of the link rather than of the request
+fn download_share(
+    paths: &PathManager,
+    metadata: &Data<MetadataManager>,
+    bandwidth: &Data<BandwidthLimiter>,
+    share: &ShareLink,
+    download: bool,
+) -> Result<HttpResponse, AWError> {
+    let tenant_paths;
+    let paths = match &share.tenant {
+        Some(tenant) => {
+            tenant_paths = paths.for_tenant(tenant);
+            &tenant_paths
+        }
+        None => paths,
+    };
+
+    let Some(bucket) = paths.get_bucket(Path::new(&share.bucket_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let Some(path) = paths.get_bucket_file(&bucket, Path::new(&share.file_name)) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+
+    let meta = match metadata.get_metadata(&path, false) {
+        Ok(m) => m,
+        Err(_e) => {
+            tracing::warn!("Failed to find metadata {}", &path.deref().display());
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    if !share.is_for(&meta) {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    match metadata.record_share_download(&share.id, &path) {
+        Ok(ShareOutcome::Blob(outcome)) => download_blob(
+            metadata,
+            bandwidth,
+            &bucket,
+            &path,
+            Ok(outcome),
+            false,
+            download,
+        ),
+        Ok(ShareOutcome::Missing) => Ok(HttpResponse::NotFound().finish()),
+        Ok(ShareOutcome::Expired | ShareOutcome::LimitReached) => Ok(HttpResponse::Gone().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to record share download {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Look up a share link, or the response to give if it can't be used
+fn find_share(metadata: &MetadataManager, id: &str) -> Result<ShareLink, HttpResponse> {
+    match metadata.get_share(id) {
+        Ok(Some(share)) if share.is_expired() || share.download_limit_reached() => {
+            Err(HttpResponse::Gone().finish())
+        }
+        Ok(Some(share)) => Ok(share),
+        Ok(None) => Err(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to get share {}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Download through a share link, or ask for its password
+/// Matches a bucket called `s`, so must come before [crate::bucket_get_file::get_file]
+#[get("/s/{id}")]
+pub async fn get_share(
+    paths: Data<PathManager>,
+    metadata: Data<MetadataManager>,
+    bandwidth: Data<BandwidthLimiter>,
+    share: WebPath<ShareId>,
+    query: Query<DownloadQuery>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("share_get").entered();
+
+    let share = match find_share(&metadata, &share.id) {
+        Ok(s) => s,
+        Err(response) => return Ok(response),
+    };
+
+    if share.password_hash.is_some() {
+        return Ok(HttpResponse::Ok()
+            .content_type(ContentType::html())
+            .body(password_page(&share.id, false, query.is_download())));
+    }
+
+    download_share(&paths, &metadata, &bandwidth, &share, query.is_download())
+}
+
+/// Download through a password protected share link
+#[post("/s/{id}")]
+pub async fn post_share(
+    paths: Data<PathManager>,
+    metadata: Data<MetadataManager>,
+    bandwidth: Data<BandwidthLimiter>,
+    share: WebPath<ShareId>,
+    query: Query<DownloadQuery>,
+    form: Form<SharePasswordForm>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("share_unlock").entered();
+
+    let share = match find_share(&metadata, &share.id) {
+        Ok(s) => s,
+        Err(response) => return Ok(response),
+    };
+
+    if let Some(hash) = &share.password_hash {
+        match hash.verify_blocking(form.into_inner().password).await {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    "Wrong password for share of {}/{}",
+                    share.bucket_name,
+                    share.file_name
+                );
+                return Ok(HttpResponse::Unauthorized()
+                    .content_type(ContentType::html())
+                    .body(password_page(&share.id, true, query.is_download())));
+            }
+            Err(e) => {
+                tracing::warn!("Failed to check share password {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+
+    download_share(&paths, &metadata, &bandwidth, &share, query.is_download())
+}
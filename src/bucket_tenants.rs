@@ -0,0 +1,228 @@
+use crate::access_key::{AccessKeyHash, generate_access_key};
+use crate::auth::Authorizer;
+use crate::metadata::MetadataManager;
+use crate::path::PathManager;
+use crate::quota::Quota;
+use crate::settings::AppSettings;
+use crate::tenant::{Tenant, normalize_host, validate_tenant_name};
+use actix_web::web::{Data, Json, Path as WebPath};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use actix_web::{get, patch, post};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// A tenant, without its admin key hash
+#[derive(Serialize)]
+pub struct TenantDetails {
+    name: String,
+    hosts: BTreeSet<String>,
+    created_at: DateTime<Utc>,
+    quota: Quota,
+    /// Plaintext admin key, only returned when it is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    admin_key: Option<String>,
+}
+
+impl From<Tenant> for TenantDetails {
+    fn from(tenant: Tenant) -> Self {
+        Self {
+            name: tenant.name,
+            hosts: tenant.hosts,
+            created_at: tenant.created_at,
+            quota: tenant.quota,
+            admin_key: None,
+        }
+    }
+}
+
+/// Normalise the hosts of a tenant, checking none of them already route to a different tenant
+fn check_hosts(
+    metadata: &MetadataManager,
+    tenant: &str,
+    hosts: &BTreeSet<String>,
+) -> anyhow::Result<Result<BTreeSet<String>, HttpResponse>> {
+    let mut normalized = BTreeSet::new();
+    for host in hosts {
+        let host = normalize_host(host);
+        if host.is_empty() {
+            return Ok(Err(HttpResponse::BadRequest().body("Invalid host")));
+        }
+
+        if metadata
+            .get_tenant_by_host(&host)?
+            .is_some_and(|t| t.name != tenant)
+        {
+            return Ok(Err(
+                HttpResponse::Conflict().body(format!("Host {} is used by another tenant", host))
+            ));
+        }
+
+        normalized.insert(host);
+    }
+    Ok(Ok(normalized))
+}
+
+/// List every tenant, needs a super admin
+#[get("/api/tenants")]
+pub async fn get_tenants(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("tenants_list").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match metadata.list_tenants() {
+        Ok(tenants) => Ok(HttpResponse::Ok().json(
+            tenants
+                .into_iter()
+                .map(TenantDetails::from)
+                .collect::<Vec<_>>(),
+        )),
+        Err(e) => {
+            tracing::warn!("Failed to list tenants {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateTenantRequest {
+    name: String,
+    #[serde(default)]
+    hosts: BTreeSet<String>,
+    #[serde(default)]
+    quota: Quota,
+}
+
+/// Create a tenant, needs a super admin. The response holds the tenant's admin key
+#[post("/api/tenants")]
+pub async fn post_tenant_create(
+    paths: Data<PathManager>,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+    body: Json<CreateTenantRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("tenant_create").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if let Err(e) = validate_tenant_name(&body.name) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    let hosts = match check_hosts(&metadata, &body.name, &body.hosts) {
+        Ok(Ok(hosts)) => hosts,
+        Ok(Err(response)) => return Ok(response),
+        Err(e) => {
+            tracing::warn!("Failed to check tenant hosts {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let admin_key = generate_access_key();
+    let tenant = Tenant {
+        name: body.name.clone(),
+        admin_key: AccessKeyHash::new(&admin_key),
+        hosts,
+        created_at: Utc::now(),
+        quota: body.quota,
+    };
+
+    match metadata.create_tenant(&tenant) {
+        Ok(true) => {}
+        Ok(false) => return Ok(HttpResponse::Conflict().body("Tenant already exists")),
+        Err(e) => {
+            tracing::warn!("Failed to create tenant {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    paths.for_tenant(&tenant.name).create_root()?;
+
+    tracing::info!("Created tenant {}", tenant.name);
+    Ok(HttpResponse::Ok().json(TenantDetails {
+        admin_key: Some(admin_key),
+        ..TenantDetails::from(tenant)
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct TenantLocation {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub struct TenantPatch {
+    /// Replaces the hosts of the tenant
+    hosts: Option<BTreeSet<String>>,
+    /// Replaces the quota of the tenant
+    quota: Option<Quota>,
+    #[serde(default)]
+    rotate_admin_key: bool,
+}
+
+/// Change the hosts or quota of a tenant, or rotate its admin key, needs a super admin
+#[patch("/api/tenants/{name}")]
+pub async fn patch_tenant(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    tenant: WebPath<TenantLocation>,
+    req: HttpRequest,
+    body: Json<TenantPatch>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("tenant_patch").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let hosts = match &body.hosts {
+        Some(hosts) => match check_hosts(&metadata, &tenant.name, hosts) {
+            Ok(Ok(hosts)) => Some(hosts),
+            Ok(Err(response)) => return Ok(response),
+            Err(e) => {
+                tracing::warn!("Failed to check tenant hosts {}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+
+    let admin_key = body.rotate_admin_key.then(generate_access_key);
+
+    let res = metadata.update_tenant::<_, ()>(&tenant.name, |t| {
+        if let Some(hosts) = &hosts {
+            t.hosts = hosts.clone();
+        }
+
+        if let Some(quota) = body.quota {
+            t.quota = quota;
+        }
+
+        if let Some(key) = &admin_key {
+            t.admin_key = AccessKeyHash::new(key);
+        }
+
+        Ok(TenantDetails {
+            admin_key: admin_key.clone(),
+            ..TenantDetails::from(t.clone())
+        })
+    });
+
+    match res {
+        Ok(Some(Ok(details))) => Ok(HttpResponse::Ok().json(details)),
+        Ok(Some(Err(()))) | Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to update tenant {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
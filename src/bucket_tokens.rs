@@ -0,0 +1,171 @@
+use crate::auth::{Authorizer, Permission};
+use crate::metadata::MetadataManager;
+use crate::path::PathManager;
+use crate::settings::AppSettings;
+use crate::tenant::TenantPaths;
+use crate::token::{ANY_BUCKET, TokenClaims, TokenOp, decode_token, sign_token};
+use actix_web::post;
+use actix_web::web::{Data, Json};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// How long a token lasts if no expiry is given
+const DEFAULT_TOKEN_LIFETIME_SECS: i64 = 60 * 60;
+
+/// Longest a token can last
+const MAX_TOKEN_LIFETIME_SECS: i64 = 365 * 24 * 60 * 60;
+
+/// Check that the request has admin permission on every one of `buckets`
+/// Only admins of the whole namespace can manage tokens for [ANY_BUCKET]
+fn is_admin_of(paths: &PathManager, authorizer: &Authorizer, buckets: &BTreeSet<String>) -> bool {
+    if buckets.is_empty() {
+        return false;
+    }
+
+    buckets.iter().all(|name| {
+        if name == ANY_BUCKET {
+            return authorizer.is_namespace_admin();
+        }
+
+        paths
+            .get_bucket(Path::new(name))
+            .is_some_and(|bucket| authorizer.can(&bucket, name, Permission::Admin))
+    })
+}
+
+#[derive(Deserialize)]
+pub struct CreateTokenRequest {
+    buckets: BTreeSet<String>,
+    #[serde(default)]
+    prefixes: BTreeSet<String>,
+    ops: BTreeSet<TokenOp>,
+    /// Seconds until the token expires, defaults to an hour
+    expires_in: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateTokenResult {
+    id: String,
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Mint a new token, needs admin permission on every bucket the token is for
+#[post("/api/tokens")]
+pub async fn post_token_create(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+    body: Json<CreateTokenRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("token_create").entered();
+
+    let Some(secret) = &settings.token_secret else {
+        return Ok(HttpResponse::NotImplemented().body("Tokens are not enabled"));
+    };
+
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+    if !is_admin_of(&paths, &authorizer, &body.buckets) {
+        tracing::warn!("Invalid key for minting token");
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if body.ops.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("Token must allow at least one operation"));
+    }
+
+    let lifetime = body.expires_in.unwrap_or(DEFAULT_TOKEN_LIFETIME_SECS);
+    if lifetime <= 0 || lifetime > MAX_TOKEN_LIFETIME_SECS {
+        return Ok(HttpResponse::BadRequest().body("Invalid expiry"));
+    }
+
+    let claims = TokenClaims::new(
+        paths.tenant().map(str::to_string),
+        body.buckets.clone(),
+        body.prefixes.clone(),
+        body.ops.clone(),
+        Utc::now() + Duration::seconds(lifetime),
+    );
+
+    match sign_token(secret, &claims) {
+        Ok(token) => {
+            tracing::info!("Created token {}", claims.id);
+            Ok(HttpResponse::Ok().json(CreateTokenResult {
+                id: claims.id,
+                token,
+                expires_at: claims.expires_at,
+            }))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to sign token {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RevokeTokenRequest {
+    /// The token to revoke, can be revoked by the admin of all of its buckets
+    token: Option<String>,
+    /// Id of the token to revoke, needs an admin of the whole namespace
+    id: Option<String>,
+}
+
+#[post("/api/tokens/revoke")]
+pub async fn post_token_revoke(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+    body: Json<RevokeTokenRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("token_revoke").entered();
+
+    let Some(secret) = &settings.token_secret else {
+        return Ok(HttpResponse::NotImplemented().body("Tokens are not enabled"));
+    };
+
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+    let (id, expires_at) = match (&body.token, &body.id) {
+        (Some(token), _) => {
+            let Some(claims) = decode_token(secret, token) else {
+                return Ok(HttpResponse::BadRequest().body("Invalid token"));
+            };
+
+            if claims.tenant.as_deref() != paths.tenant()
+                || !is_admin_of(&paths, &authorizer, &claims.buckets)
+            {
+                return Ok(HttpResponse::Unauthorized().finish());
+            }
+
+            (claims.id, claims.expires_at)
+        }
+        (None, Some(id)) => {
+            if !authorizer.is_namespace_admin() {
+                return Ok(HttpResponse::Unauthorized().finish());
+            }
+
+            // Without the token we don't know when it expires, so keep it revoked for as long as any token can live
+            (
+                id.clone(),
+                Utc::now() + Duration::seconds(MAX_TOKEN_LIFETIME_SECS),
+            )
+        }
+        (None, None) => return Ok(HttpResponse::BadRequest().body("No token given")),
+    };
+
+    match metadata.revoke_token(&id, expires_at) {
+        Ok(()) => {
+            tracing::info!("Revoked token {}", id);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Err(e) => {
+            tracing::warn!("Failed to revoke token {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
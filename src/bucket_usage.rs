@@ -0,0 +1,102 @@
+use crate::auth::{Authorizer, Permission};
+use crate::bucket::BucketLocation;
+use crate::metadata::MetadataManager;
+use crate::quota::{Quota, Usage};
+use crate::settings::AppSettings;
+use crate::tenant::TenantPaths;
+use actix_web::get;
+use actix_web::web::{Data, Path as WebPath};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use serde::Serialize;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct UsageReport {
+    #[serde(flatten)]
+    usage: Usage,
+    quota: Quota,
+    /// Is the usage past the soft limits of the quota
+    over_soft_quota: bool,
+}
+
+impl UsageReport {
+    fn new(usage: Usage, quota: Quota) -> Self {
+        Self {
+            usage,
+            quota,
+            over_soft_quota: quota.warns(usage),
+        }
+    }
+}
+
+/// Storage used by a bucket, needs admin permission on the bucket
+#[get("/api/bucket/{name}/usage")]
+pub async fn get_bucket_usage(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    file: WebPath<BucketLocation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("bucket_usage").entered();
+
+    let bucket = match paths.get_bucket(Path::new(&file.name)) {
+        Some(b) => b,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    if !Authorizer::new(&req, &settings, &metadata).can(&bucket, &file.name, Permission::Admin) {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let report = metadata.get_usage(&bucket).and_then(|usage| {
+        let quota = metadata
+            .get_bucket_config(&bucket)?
+            .map(|c| c.quota)
+            .unwrap_or_default();
+        Ok(UsageReport::new(usage, quota))
+    });
+
+    match report {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(e) => {
+            tracing::warn!("Failed to get usage of bucket {}: {}", &file.name, e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Storage used by every bucket of the request's namespace, needs an admin of the namespace
+/// Only tenants have a quota, the default namespace is unlimited
+#[get("/api/usage")]
+pub async fn get_usage(
+    paths: TenantPaths,
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("usage").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_namespace_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let report = metadata.get_usage(&paths.get_root()).and_then(|usage| {
+        let quota = match paths.tenant() {
+            Some(tenant) => metadata
+                .get_tenant(tenant)?
+                .map(|t| t.quota)
+                .unwrap_or_default(),
+            None => Quota::default(),
+        };
+        Ok(UsageReport::new(usage, quota))
+    });
+
+    match report {
+        Ok(report) => Ok(HttpResponse::Ok().json(report)),
+        Err(e) => {
+            tracing::warn!("Failed to get usage {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
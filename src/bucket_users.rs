@@ -0,0 +1,325 @@
+use crate::access_key::PasswordHash;
+use crate::auth::Authorizer;
+use crate::bucket::double_option;
+use crate::metadata::MetadataManager;
+use crate::settings::AppSettings;
+use crate::token::bearer_token;
+use crate::user::{Role, SESSION_PREFIX, Session, User, generate_session_token, validate_username};
+use actix_web::web::{Data, Json, Path as WebPath};
+use actix_web::{Error as AWError, HttpRequest, HttpResponse};
+use actix_web::{delete, get, patch, post};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// How long a login lasts
+const SESSION_LIFETIME_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginResult {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Log in as a user, the returned token is used as `Authorization: Bearer <token>`
+#[post("/api/login")]
+pub async fn post_login(
+    metadata: Data<MetadataManager>,
+    body: Json<LoginRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("login").entered();
+
+    let user = match metadata.get_user(&body.username) {
+        Ok(u) => u,
+        Err(e) => {
+            tracing::warn!("Failed to get user {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    // Unknown users are checked against a dummy hash, so they can't be told apart by how long the check takes
+    let hash = user
+        .as_ref()
+        .map_or(PasswordHash::dummy(), |u| &u.password_hash);
+    let verified = match hash.verify_blocking(body.password.clone()).await {
+        Ok(v) => v,
+        Err(e) => {
+            tracing::warn!("Failed to check password {}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+    if !verified || user.is_none() {
+        tracing::warn!("Failed login for {}", body.username);
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let token = generate_session_token();
+    let session = Session {
+        username: body.username.clone(),
+        expires_at: Utc::now() + Duration::seconds(SESSION_LIFETIME_SECS),
+    };
+
+    match metadata.create_session(&token, &session) {
+        Ok(()) => {
+            tracing::info!("{} logged in", body.username);
+            Ok(HttpResponse::Ok().json(LoginResult {
+                token,
+                expires_at: session.expires_at,
+            }))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to create session {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[post("/api/logout")]
+pub async fn post_logout(
+    metadata: Data<MetadataManager>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("logout").entered();
+
+    let Some(token) = bearer_token(&req).filter(|t| t.starts_with(SESSION_PREFIX)) else {
+        return Ok(HttpResponse::Unauthorized().finish());
+    };
+
+    match metadata.remove_session(token) {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Ok(HttpResponse::Unauthorized().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to remove session {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// A user, without their password hash
+#[derive(Serialize)]
+pub struct UserDetails {
+    username: String,
+    role: Option<Role>,
+    grants: BTreeMap<String, Role>,
+    created_at: DateTime<Utc>,
+}
+
+impl From<User> for UserDetails {
+    fn from(user: User) -> Self {
+        Self {
+            username: user.username,
+            role: user.role,
+            grants: user.grants,
+            created_at: user.created_at,
+        }
+    }
+}
+
+/// List every user, needs a super admin
+#[get("/api/users")]
+pub async fn get_users(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("users_list").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match metadata.list_users() {
+        Ok(users) => {
+            Ok(HttpResponse::Ok()
+                .json(users.into_iter().map(UserDetails::from).collect::<Vec<_>>()))
+        }
+        Err(e) => {
+            tracing::warn!("Failed to list users {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    username: String,
+    password: String,
+    role: Option<Role>,
+    #[serde(default)]
+    grants: BTreeMap<String, Role>,
+}
+
+/// Create a user, needs a super admin
+/// The global bucket creation key is a super admin, so it can be used to create the first admin user
+#[post("/api/users")]
+pub async fn post_user_create(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    req: HttpRequest,
+    body: Json<CreateUserRequest>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("user_create").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    if let Err(e) = validate_username(&body.username) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+
+    if body.password.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("Invalid password"));
+    }
+
+    let password_hash = match PasswordHash::new_blocking(body.password.clone()).await {
+        Ok(h) => h,
+        Err(e) => {
+            tracing::warn!("{}", e);
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    };
+
+    let user = User {
+        username: body.username.clone(),
+        password_hash,
+        role: body.role,
+        grants: body.grants.clone(),
+        created_at: Utc::now(),
+    };
+
+    match metadata.create_user(&user) {
+        Ok(true) => {
+            tracing::info!("Created user {}", user.username);
+            Ok(HttpResponse::Ok().json(UserDetails::from(user)))
+        }
+        Ok(false) => Ok(HttpResponse::Conflict().body("User already exists")),
+        Err(e) => {
+            tracing::warn!("Failed to create user {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct UserLocation {
+    username: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserPatch {
+    password: Option<String>,
+
+    /// `null` removes the user's global role
+    #[serde(default, deserialize_with = "double_option")]
+    role: Option<Option<Role>>,
+
+    /// Grants to change, a `null` role removes the grant
+    #[serde(default)]
+    grants: BTreeMap<String, Option<Role>>,
+}
+
+/// Change a user, needs a super admin. Users can change their own password
+#[patch("/api/users/{username}")]
+pub async fn patch_user(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    user: WebPath<UserLocation>,
+    req: HttpRequest,
+    body: Json<UserPatch>,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("user_patch").entered();
+
+    let authorizer = Authorizer::new(&req, &settings, &metadata);
+    let is_self = authorizer
+        .user()
+        .is_some_and(|u| u.username == user.username);
+    let only_password = body.role.is_none() && body.grants.is_empty();
+
+    let allowed = authorizer.is_super_admin() || (is_self && only_password);
+    if !allowed {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    let password_hash = match body.password.as_deref() {
+        Some("") => return Ok(HttpResponse::BadRequest().body("Invalid password")),
+        Some(password) => match PasswordHash::new_blocking(password.to_string()).await {
+            Ok(h) => Some(h),
+            Err(e) => {
+                tracing::warn!("{}", e);
+                return Ok(HttpResponse::InternalServerError().finish());
+            }
+        },
+        None => None,
+    };
+
+    let res = metadata.update_user::<_, ()>(&user.username, |u| {
+        if let Some(hash) = &password_hash {
+            u.password_hash = hash.clone();
+        }
+
+        if let Some(role) = body.role {
+            u.role = role;
+        }
+
+        for (bucket, role) in &body.grants {
+            match role {
+                Some(role) => u.grants.insert(bucket.clone(), *role),
+                None => u.grants.remove(bucket),
+            };
+        }
+
+        Ok(UserDetails::from(u.clone()))
+    });
+
+    match res {
+        Ok(Some(Ok(details))) => {
+            // Changing the password logs out everywhere else, users changing their own stay logged in where they did it
+            let keep = bearer_token(&req).filter(|t| is_self && t.starts_with(SESSION_PREFIX));
+            if password_hash.is_some()
+                && let Err(e) = metadata.remove_user_sessions(&user.username, keep)
+            {
+                tracing::warn!("Failed to remove sessions {}", e);
+            }
+            Ok(HttpResponse::Ok().json(details))
+        }
+        Ok(Some(Err(()))) | Ok(None) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to update user {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Remove a user and end their sessions, needs a super admin
+#[delete("/api/users/{username}")]
+pub async fn delete_user(
+    metadata: Data<MetadataManager>,
+    settings: Data<AppSettings>,
+    user: WebPath<UserLocation>,
+    req: HttpRequest,
+) -> Result<HttpResponse, AWError> {
+    let _span = tracing::info_span!("user_delete").entered();
+
+    if !Authorizer::new(&req, &settings, &metadata).is_super_admin() {
+        return Ok(HttpResponse::Unauthorized().finish());
+    }
+
+    match metadata.remove_user(&user.username) {
+        Ok(true) => {
+            tracing::info!("Removed user {}", user.username);
+            Ok(HttpResponse::Ok().finish())
+        }
+        Ok(false) => Ok(HttpResponse::NotFound().finish()),
+        Err(e) => {
+            tracing::warn!("Failed to remove user {}", e);
+            Ok(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
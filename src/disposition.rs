@@ -0,0 +1,167 @@
+//! Original filenames and Content-Disposition
+//! Multipart uploads carry the name of the file on the uploader's machine, which is kept so downloads are saved under
+//! the same name. Blobs are shown inline by default, a blob can be set to always download as an attachment, and any
+//! download can ask to be an attachment with `?download=1`
+
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+use serde::{Deserialize, Serialize};
+
+/// Header used to set the disposition of a blob on upload
+pub const DISPOSITION_HEADER: &str = "X-Blob-Disposition";
+
+/// Longest original filename that is kept, in bytes
+const MAX_FILENAME_LEN: usize = 255;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Disposition {
+    #[default]
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "inline" => Some(Disposition::Inline),
+            "attachment" => Some(Disposition::Attachment),
+            _ => None,
+        }
+    }
+}
+
+/// Query of a download, `?download=1` or `?download=true` makes it an attachment
+#[derive(Deserialize, Debug, Default)]
+pub struct DownloadQuery {
+    download: Option<String>,
+}
+
+impl DownloadQuery {
+    pub fn is_download(&self) -> bool {
+        self.download
+            .as_deref()
+            .is_some_and(|d| d == "1" || d.eq_ignore_ascii_case("true"))
+    }
+}
+
+/// Clean up a filename given by an uploader, `None` if nothing usable is left
+/// Only the last path component is kept, as some browsers send the full path, and control characters are removed
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let mut name = name.trim().to_string();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+
+    if name.len() > MAX_FILENAME_LEN {
+        let mut end = MAX_FILENAME_LEN;
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+    }
+    Some(name)
+}
+
+/// Content-Disposition of a download called `filename`
+/// Non-ASCII names are sent as an RFC 5987 `filename*`, with an ASCII `filename` for clients that don't support it
+pub fn content_disposition(disposition: Disposition, filename: &str) -> ContentDisposition {
+    let disposition = match disposition {
+        Disposition::Inline => DispositionType::Inline,
+        Disposition::Attachment => DispositionType::Attachment,
+    };
+
+    let mut parameters = Vec::with_capacity(2);
+    if filename.is_ascii() {
+        parameters.push(DispositionParam::Filename(filename.to_string()));
+    } else {
+        let fallback: String = filename
+            .chars()
+            .map(|c| if c.is_ascii() { c } else { '_' })
+            .collect();
+        parameters.push(DispositionParam::Filename(fallback));
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+
+    ContentDisposition {
+        disposition,
+        parameters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(disposition: Disposition, filename: &str) -> String {
+        content_disposition(disposition, filename).to_string()
+    }
+
+    #[test]
+    fn ascii_names_are_plain_filenames() {
+        assert_eq!(
+            header(Disposition::Inline, "report.pdf"),
+            "inline; filename=\"report.pdf\""
+        );
+        assert_eq!(
+            header(Disposition::Attachment, "say \"hi\".txt"),
+            "attachment; filename=\"say \\\"hi\\\".txt\""
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_get_an_encoded_filename_and_an_ascii_fallback() {
+        assert_eq!(
+            header(Disposition::Attachment, "résumé.pdf"),
+            "attachment; filename=\"r_sum_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9.pdf"
+        );
+        assert_eq!(
+            header(Disposition::Inline, "日本 語.txt"),
+            "inline; filename=\"__ _.txt\"; filename*=UTF-8''%E6%97%A5%E6%9C%AC%20%E8%AA%9E.txt"
+        );
+    }
+
+    #[test]
+    fn sanitized_names_keep_only_the_last_component() {
+        assert_eq!(
+            sanitize_filename("C:\\Users\\me\\photo.jpg").as_deref(),
+            Some("photo.jpg")
+        );
+        assert_eq!(
+            sanitize_filename("../../etc/passwd").as_deref(),
+            Some("passwd")
+        );
+        assert_eq!(
+            sanitize_filename(" bad\r\nname.txt ").as_deref(),
+            Some("badname.txt")
+        );
+        assert_eq!(sanitize_filename("dir/.."), None);
+        assert_eq!(sanitize_filename("  "), None);
+    }
+
+    #[test]
+    fn long_names_are_cut_on_a_character_boundary() {
+        let name = "é".repeat(MAX_FILENAME_LEN);
+        let sanitized = sanitize_filename(&name).unwrap();
+        assert!(sanitized.len() <= MAX_FILENAME_LEN);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn download_query_accepts_1_and_true() {
+        let query = |download: &str| DownloadQuery {
+            download: Some(download.to_string()),
+        };
+        assert!(query("1").is_download());
+        assert!(query("TRUE").is_download());
+        assert!(!query("0").is_download());
+        assert!(!DownloadQuery::default().is_download());
+    }
+}
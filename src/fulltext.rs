@@ -0,0 +1,269 @@
+//! Optional full-text index over the content of text blobs
+//! Without the `full-text-search` feature this is a no-op so callers don't need to care if it is enabled
+//!
+//! Changes are staged as blobs are uploaded, changed and deleted, and committed in batches by
+//! [commit_periodically], as a tantivy commit is slow. Searches see them once they are committed
+
+use crate::metadata::BlobMetadata;
+use actix_web::web::{self, Data};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Blobs larger than this aren't indexed
+pub const MAX_INDEXED_SIZE: u64 = 1024 * 1024;
+
+/// How often staged changes are committed
+const COMMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Should a blob with this metadata have its content indexed
+pub fn should_index(meta: &BlobMetadata) -> bool {
+    let ct = meta.content_type.as_str();
+    let is_text = ct.starts_with("text")
+        || ct == "application/json"
+        || ct == "application/xml"
+        || ct.ends_with("+json")
+        || ct.ends_with("+xml");
+
+    is_text && meta.size.is_some_and(|s| s <= MAX_INDEXED_SIZE)
+}
+
+/// Index the content of the blob at `path` if `index` is set, otherwise remove it from the index
+/// Reading the blob and updating the index block, so this is done off the async workers
+pub async fn update_blob(fulltext: &Data<FullTextIndex>, path: PathBuf, index: bool) {
+    if cfg!(not(feature = "full-text-search")) {
+        return;
+    }
+
+    let fulltext = Data::clone(fulltext);
+    let res = web::block(move || {
+        let key = path.as_os_str().as_bytes();
+        let res = match std::fs::read_to_string(&path) {
+            Ok(content) if index => fulltext.index_blob(key, &content),
+            Ok(_) => fulltext.remove_blob(key),
+            Err(_e) => {
+                tracing::info!("Not indexing {}, not valid UTF-8", path.display());
+                fulltext.remove_blob(key)
+            }
+        };
+        if let Err(e) = res {
+            tracing::warn!(
+                "Failed to update full-text index for {}: {}",
+                path.display(),
+                e
+            );
+        }
+    })
+    .await;
+
+    if let Err(e) = res {
+        tracing::warn!("Failed to update full-text index {}", e);
+    }
+}
+
+/// Commit staged changes to the index every [COMMIT_INTERVAL], for as long as the server runs
+pub async fn commit_periodically(fulltext: Data<FullTextIndex>) {
+    if cfg!(not(feature = "full-text-search")) {
+        return;
+    }
+
+    let mut interval = actix_rt::time::interval(COMMIT_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let fulltext = Data::clone(&fulltext);
+        match web::block(move || fulltext.commit()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("Failed to commit full-text index {}", e),
+            Err(e) => tracing::warn!("Failed to commit full-text index {}", e),
+        }
+    }
+}
+
+/// A blob that matched a full-text search
+pub struct TextMatch {
+    /// The metadata key of the blob
+    pub blob_key: Vec<u8>,
+    /// HTML snippet of the matching content, matches are wrapped in `<b>`
+    pub snippet: String,
+}
+
+#[cfg(feature = "full-text-search")]
+pub use enabled::FullTextIndex;
+
+#[cfg(not(feature = "full-text-search"))]
+pub use disabled::FullTextIndex;
+
+#[cfg(feature = "full-text-search")]
+mod enabled {
+    use super::TextMatch;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tantivy::collector::TopDocs;
+    use tantivy::directory::MmapDirectory;
+    use tantivy::query::QueryParser;
+    use tantivy::schema::{Field, INDEXED, STORED, Schema, TEXT, Value};
+    use tantivy::snippet::SnippetGenerator;
+    use tantivy::{Index, IndexReader, IndexWriter, TantivyDocument, Term, doc};
+
+    /// Memory used by the index writer before it flushes to disk
+    const WRITER_MEMORY: usize = 50_000_000;
+
+    pub struct FullTextIndex {
+        index: Index,
+        reader: IndexReader,
+        writer: Mutex<IndexWriter>,
+        /// Are there changes that haven't been committed
+        pending: AtomicBool,
+        path: Field,
+        content: Field,
+    }
+
+    impl FullTextIndex {
+        pub fn new() -> anyhow::Result<Self> {
+            let mut schema = Schema::builder();
+            let path = schema.add_bytes_field("path", INDEXED | STORED);
+            let content = schema.add_text_field("content", TEXT | STORED);
+            let schema = schema.build();
+
+            let dir = "./storage_root/fulltext";
+            std::fs::create_dir_all(dir)?;
+            let index = Index::open_or_create(MmapDirectory::open(dir)?, schema)?;
+            let reader = index.reader()?;
+            let writer = Mutex::new(index.writer(WRITER_MEMORY)?);
+
+            Ok(Self {
+                index,
+                reader,
+                writer,
+                pending: AtomicBool::new(false),
+                path,
+                content,
+            })
+        }
+
+        fn writer(&self) -> anyhow::Result<std::sync::MutexGuard<'_, IndexWriter>> {
+            self.writer
+                .lock()
+                .map_err(|_| anyhow::anyhow!("Full-text index writer poisoned"))
+        }
+
+        /// Stage a change, which is saved by the next [Self::commit]
+        fn with_writer(
+            &self,
+            f: impl FnOnce(&mut IndexWriter) -> anyhow::Result<()>,
+        ) -> anyhow::Result<()> {
+            f(&mut *self.writer()?)?;
+            self.pending.store(true, Ordering::Release);
+            Ok(())
+        }
+
+        /// Save staged changes and make them visible to searches, if there are any
+        pub fn commit(&self) -> anyhow::Result<()> {
+            if !self.pending.swap(false, Ordering::AcqRel) {
+                return Ok(());
+            }
+
+            let _span = tracing::info_span!("fulltext_commit").entered();
+
+            let res = self.writer()?.commit();
+            if let Err(e) = res {
+                // Try again next time
+                self.pending.store(true, Ordering::Release);
+                return Err(e.into());
+            }
+            self.reader.reload()?;
+            Ok(())
+        }
+
+        /// Add, or replace, the content of a blob
+        pub fn index_blob(&self, blob_key: &[u8], content: &str) -> anyhow::Result<()> {
+            let _span = tracing::info_span!("fulltext_index").entered();
+
+            self.with_writer(|writer| {
+                writer.delete_term(Term::from_field_bytes(self.path, blob_key));
+                writer.add_document(doc!(
+                    self.path => blob_key,
+                    self.content => content,
+                ))?;
+                Ok(())
+            })
+        }
+
+        pub fn remove_blob(&self, blob_key: &[u8]) -> anyhow::Result<()> {
+            let _span = tracing::info_span!("fulltext_remove").entered();
+
+            self.with_writer(|writer| {
+                writer.delete_term(Term::from_field_bytes(self.path, blob_key));
+                Ok(())
+            })
+        }
+
+        /// Find blobs matching `query`, in order of relevance
+        /// `filter` is given the key of each blob and decides if it can be included, this is checked before the limit
+        /// is applied
+        pub fn search(
+            &self,
+            query: &str,
+            limit: usize,
+            filter: impl Fn(&[u8]) -> bool,
+        ) -> anyhow::Result<Vec<TextMatch>> {
+            let _span = tracing::info_span!("fulltext_search").entered();
+
+            let searcher = self.reader.searcher();
+            let parser = QueryParser::for_index(&self.index, vec![self.content]);
+            let query = parser.parse_query(query)?;
+            let snippets = SnippetGenerator::create(&searcher, &*query, self.content)?;
+
+            // Fetch extra results as some will likely be filtered out
+            let top = searcher.search(&query, &TopDocs::with_limit(limit * 4))?;
+
+            let mut out = Vec::new();
+            for (_score, address) in top {
+                if out.len() >= limit {
+                    break;
+                }
+
+                let doc: TantivyDocument = searcher.doc(address)?;
+                let Some(blob_key) = doc.get_first(self.path).and_then(|v| v.as_bytes()) else {
+                    continue;
+                };
+
+                if !filter(blob_key) {
+                    continue;
+                }
+
+                out.push(TextMatch {
+                    blob_key: blob_key.to_vec(),
+                    snippet: snippets.snippet_from_doc(&doc).to_html(),
+                });
+            }
+
+            Ok(out)
+        }
+    }
+}
+
+#[cfg(not(feature = "full-text-search"))]
+mod disabled {
+    /// Stand in used when full-text search is disabled, does nothing
+    pub struct FullTextIndex;
+
+    impl FullTextIndex {
+        pub fn new() -> anyhow::Result<Self> {
+            Ok(Self)
+        }
+
+        pub fn index_blob(&self, _blob_key: &[u8], _content: &str) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        pub fn remove_blob(&self, _blob_key: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        pub fn commit(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+}
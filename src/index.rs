@@ -0,0 +1,386 @@
+use crate::metadata::BlobMetadata;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+
+// Secondary index key layouts, every key ends with the blob path so it can be recovered from the key alone.
+// Variable length parts are terminated with `\0`, which can't appear in header values or paths
+const TAG: u8 = b't';
+const META: u8 = b'm';
+const CONTENT_TYPE: u8 = b'c';
+const DELETED: u8 = b'x';
+const CREATED: u8 = b'd';
+const SIZE: u8 = b's';
+
+fn key_with_parts(kind: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let mut key = vec![kind, 0];
+    for part in parts {
+        key.extend_from_slice(part);
+        key.push(0);
+    }
+    key
+}
+
+fn key_with_number(kind: u8, number: u64, blob_key: &[u8]) -> Vec<u8> {
+    let mut key = vec![kind, 0];
+    key.extend_from_slice(&number.to_be_bytes());
+    key.extend_from_slice(blob_key);
+    key
+}
+
+/// Map a timestamp to a number that sorts in the same order
+fn timestamp_key(time: DateTime<Utc>) -> u64 {
+    (time.timestamp_millis() as u64) ^ (1 << 63)
+}
+
+/// All the index keys for a blob with the given metadata
+pub fn index_keys(blob_key: &[u8], meta: &BlobMetadata) -> BTreeSet<Vec<u8>> {
+    let mut keys = BTreeSet::new();
+
+    let mut with_blob = |mut key: Vec<u8>| {
+        key.extend_from_slice(blob_key);
+        keys.insert(key);
+    };
+
+    for tag in &meta.tags {
+        with_blob(key_with_parts(TAG, &[tag.as_bytes()]));
+    }
+    for (k, v) in &meta.user_metadata {
+        with_blob(key_with_parts(META, &[k.as_bytes(), v.as_bytes()]));
+    }
+    with_blob(key_with_parts(
+        CONTENT_TYPE,
+        &[meta.content_type.as_bytes()],
+    ));
+    if meta.deletion_date.is_some() {
+        with_blob(key_with_parts(DELETED, &[]));
+    }
+
+    if let Some(created_at) = meta.created_at {
+        keys.insert(key_with_number(
+            CREATED,
+            timestamp_key(created_at),
+            blob_key,
+        ));
+    }
+    if let Some(size) = meta.size {
+        keys.insert(key_with_number(SIZE, size, blob_key));
+    }
+
+    keys
+}
+
+/// Should deleted blobs be included in search results
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedFilter {
+    /// Only blobs that haven't been deleted
+    #[default]
+    Exclude,
+    /// Only blobs that have been deleted
+    Only,
+    /// Everything
+    Include,
+}
+
+/// Filters for a metadata search, all given filters must match
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchFilter {
+    pub tag: Option<String>,
+    /// Metadata entry in the form `key:value`
+    pub meta: Option<String>,
+    pub content_type: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub deleted: DeletedFilter,
+}
+
+/// A range of index keys that contains every blob matching a filter, the candidates still need to be checked with
+/// [SearchFilter::matches]
+pub enum IndexScan {
+    /// Every key with this prefix, blob path follows the last `\0`
+    Prefix(Vec<u8>),
+    /// Every key in this range, blob path follows a fixed size number
+    Range(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    /// No index can be used, every blob must be checked
+    All,
+}
+
+impl IndexScan {
+    /// Get the blob path from an index key found by this scan
+    pub fn blob_key<'a>(&self, index_key: &'a [u8]) -> Option<&'a [u8]> {
+        match self {
+            IndexScan::Prefix(_) => {
+                let split = index_key.iter().rposition(|b| *b == 0)?;
+                Some(&index_key[split + 1..])
+            }
+            IndexScan::Range(..) => index_key.get(2 + 8..),
+            IndexScan::All => Some(index_key),
+        }
+    }
+}
+
+impl SearchFilter {
+    /// Parse the `meta` filter into its key and value
+    fn meta_entry(&self) -> Option<(String, &str)> {
+        let (k, v) = self.meta.as_ref()?.split_once(':')?;
+        Some((k.to_ascii_lowercase(), v))
+    }
+
+    /// Pick the index that is likely to narrow down the results the most
+    pub fn plan(&self) -> IndexScan {
+        if let Some(tag) = &self.tag {
+            return IndexScan::Prefix(key_with_parts(TAG, &[tag.as_bytes()]));
+        }
+
+        if let Some((k, v)) = self.meta_entry() {
+            return IndexScan::Prefix(key_with_parts(META, &[k.as_bytes(), v.as_bytes()]));
+        }
+
+        if let Some(ct) = &self.content_type {
+            return IndexScan::Prefix(key_with_parts(CONTENT_TYPE, &[ct.as_bytes()]));
+        }
+
+        if self.deleted == DeletedFilter::Only {
+            return IndexScan::Prefix(key_with_parts(DELETED, &[]));
+        }
+
+        if self.created_after.is_some() || self.created_before.is_some() {
+            let start = self.created_after.map_or(0, timestamp_key);
+            let end = self.created_before.map_or(u64::MAX, timestamp_key);
+            return IndexScan::Range(
+                Bound::Included(key_with_number(CREATED, start, &[])),
+                Bound::Included(key_with_number(CREATED, end, &[0xFF])),
+            );
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            return IndexScan::Range(
+                Bound::Included(key_with_number(SIZE, self.min_size.unwrap_or(0), &[])),
+                Bound::Included(key_with_number(
+                    SIZE,
+                    self.max_size.unwrap_or(u64::MAX),
+                    &[0xFF],
+                )),
+            );
+        }
+
+        IndexScan::All
+    }
+
+    /// Check if a blob matches every part of this filter
+    pub fn matches(&self, meta: &BlobMetadata) -> bool {
+        if let Some(tag) = &self.tag
+            && !meta.tags.contains(tag)
+        {
+            return false;
+        }
+
+        if self.meta.is_some() {
+            match self.meta_entry() {
+                Some((k, v)) if meta.user_metadata.get(&k).is_some_and(|m| m == v) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(ct) = &self.content_type
+            && *ct != meta.content_type
+        {
+            return false;
+        }
+
+        let deleted = meta.deletion_date.is_some();
+        match self.deleted {
+            DeletedFilter::Exclude if deleted => return false,
+            DeletedFilter::Only if !deleted => return false,
+            _ => {}
+        }
+
+        if self.min_size.is_some() || self.max_size.is_some() {
+            let Some(size) = meta.size else {
+                return false;
+            };
+            if self.min_size.is_some_and(|min| size < min)
+                || self.max_size.is_some_and(|max| size > max)
+            {
+                return false;
+            }
+        }
+
+        if self.created_after.is_some() || self.created_before.is_some() {
+            let Some(created_at) = meta.created_at else {
+                return false;
+            };
+            if self.created_after.is_some_and(|t| created_at < t)
+                || self.created_before.is_some_and(|t| created_at > t)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn blob(tags: &[&str], content_type: &str, size: u64, created_year: i32) -> BlobMetadata {
+        let mut meta = BlobMetadata::default();
+        meta.tags = tags.iter().map(|t| t.to_string()).collect();
+        meta.user_metadata = [("owner".to_string(), format!("user{}", size % 2))].into();
+        meta.content_type = content_type.to_string();
+        meta.size = Some(size);
+        meta.created_at = Some(Utc.with_ymd_and_hms(created_year, 1, 1, 0, 0, 0).unwrap());
+        meta
+    }
+
+    fn blobs() -> Vec<(&'static [u8], BlobMetadata)> {
+        vec![
+            (b"/a/cat.png", blob(&["cat"], "image/png", 10, 1960)),
+            (b"/a/cats.png", blob(&["cats"], "image/png", 300, 2020)),
+            (
+                b"/a/notes.txt",
+                blob(&["cat", "text"], "text/plain", 5, 2024),
+            ),
+            (b"/b/old.bin", {
+                let mut meta = blob(&[], "application/octet-stream", 1 << 40, 2000);
+                meta.deletion_date = Some(Utc::now());
+                meta
+            }),
+        ]
+    }
+
+    /// Find blobs the way a search does, through the planned index scan then the filter
+    fn search(filter: &SearchFilter) -> BTreeSet<Vec<u8>> {
+        let blobs = blobs();
+        let index: BTreeSet<Vec<u8>> = blobs
+            .iter()
+            .flat_map(|(key, meta)| index_keys(key, meta))
+            .collect();
+
+        let scan = filter.plan();
+        let candidates: Vec<&[u8]> = match &scan {
+            IndexScan::Prefix(prefix) => index
+                .iter()
+                .filter(|k| k.starts_with(prefix))
+                .filter_map(|k| scan.blob_key(k))
+                .collect(),
+            IndexScan::Range(start, end) => index
+                .range::<Vec<u8>, _>((start.as_ref(), end.as_ref()))
+                .filter_map(|k| scan.blob_key(k))
+                .collect(),
+            IndexScan::All => blobs.iter().map(|(key, _)| *key).collect(),
+        };
+
+        candidates
+            .into_iter()
+            .filter(|key| {
+                blobs
+                    .iter()
+                    .any(|(k, meta)| k == key && filter.matches(meta))
+            })
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    /// The blobs a filter matches without using the index
+    fn expected(filter: &SearchFilter) -> BTreeSet<Vec<u8>> {
+        blobs()
+            .into_iter()
+            .filter(|(_, meta)| filter.matches(meta))
+            .map(|(key, _)| key.to_vec())
+            .collect()
+    }
+
+    fn keys(keys: &[&str]) -> BTreeSet<Vec<u8>> {
+        keys.iter().map(|k| k.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn index_scans_find_every_match() {
+        let filters = [
+            SearchFilter {
+                tag: Some("cat".to_string()),
+                ..Default::default()
+            },
+            SearchFilter {
+                meta: Some("Owner:user0".to_string()),
+                ..Default::default()
+            },
+            SearchFilter {
+                content_type: Some("image/png".to_string()),
+                min_size: Some(100),
+                ..Default::default()
+            },
+            SearchFilter {
+                deleted: DeletedFilter::Only,
+                ..Default::default()
+            },
+            SearchFilter {
+                created_before: Some(Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap()),
+                deleted: DeletedFilter::Include,
+                ..Default::default()
+            },
+            SearchFilter {
+                min_size: Some(5),
+                max_size: Some(300),
+                ..Default::default()
+            },
+            SearchFilter::default(),
+        ];
+
+        for filter in &filters {
+            assert_eq!(search(filter), expected(filter), "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn tag_prefix_doesnt_match_longer_tags() {
+        let filter = SearchFilter {
+            tag: Some("cat".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(search(&filter), keys(&["/a/cat.png", "/a/notes.txt"]));
+    }
+
+    #[test]
+    fn created_range_orders_dates_before_1970() {
+        let filter = SearchFilter {
+            created_after: Some(Utc.with_ymd_and_hms(1950, 1, 1, 0, 0, 0).unwrap()),
+            created_before: Some(Utc.with_ymd_and_hms(2010, 1, 1, 0, 0, 0).unwrap()),
+            deleted: DeletedFilter::Include,
+            ..Default::default()
+        };
+        assert!(matches!(filter.plan(), IndexScan::Range(..)));
+        assert_eq!(search(&filter), keys(&["/a/cat.png", "/b/old.bin"]));
+    }
+
+    #[test]
+    fn deleted_blobs_are_excluded_by_default() {
+        let filter = SearchFilter {
+            min_size: Some(1 << 30),
+            ..Default::default()
+        };
+        assert!(search(&filter).is_empty());
+    }
+
+    #[test]
+    fn most_selective_index_is_used() {
+        let filter = SearchFilter {
+            tag: Some("cat".to_string()),
+            content_type: Some("image/png".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            matches!(filter.plan(), IndexScan::Prefix(prefix) if prefix == key_with_parts(TAG, &[b"cat"]))
+        );
+        assert!(matches!(SearchFilter::default().plan(), IndexScan::All));
+    }
+}
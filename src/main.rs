@@ -1,14 +1,46 @@
+pub mod access_key;
+pub mod analytics;
+pub mod auth;
+pub mod bandwidth;
 #[deny(clippy::unwrap_used)]
 pub mod bucket;
+pub mod bucket_analytics;
+pub mod bucket_config;
 pub mod bucket_get_file;
+pub mod bucket_presign;
+pub mod bucket_search;
+pub mod bucket_share;
+pub mod bucket_tenants;
+pub mod bucket_tokens;
+pub mod bucket_usage;
+pub mod bucket_users;
+pub mod disposition;
 pub mod file_location;
+pub mod fulltext;
+pub mod index;
 pub mod metadata;
 pub mod path;
+pub mod presign;
+pub mod quota;
+pub mod rate_limit;
+pub mod redact;
+pub mod response_headers;
 pub mod settings;
+pub mod share;
+pub mod sniff;
+pub mod tenant;
+pub mod token;
+pub mod upload_policy;
+pub mod user;
+pub mod visibility;
 
+use crate::bandwidth::BandwidthLimiter;
+use crate::fulltext::FullTextIndex;
 use crate::metadata::MetadataManager;
 use crate::path::PathManager;
+use crate::rate_limit::RateLimiter;
 use actix_cors::Cors;
+use actix_web::dev::Service;
 use actix_web::middleware::{Compress, Logger, NormalizePath, TrailingSlash};
 use actix_web::web::Data;
 use actix_web::{App, Error as AWError, HttpResponse, HttpServer, http, web};
@@ -35,34 +67,103 @@ async fn main() -> anyhow::Result<()> {
     let settings = Data::new(settings::AppSettings::from_env()?);
     let path_manager = Data::new(PathManager::new(Data::clone(&settings)));
     let metadata_manager = Data::new(MetadataManager::new()?);
+    let fulltext_index = Data::new(FullTextIndex::new()?);
+    let rate_limiter = Data::new(RateLimiter::new(settings.rate_limit.clone()));
+    let bandwidth_limiter = Data::new(BandwidthLimiter::new(settings.bandwidth));
+
+    let committed_index = Data::clone(&fulltext_index);
+    actix_rt::spawn(fulltext::commit_periodically(Data::clone(&fulltext_index)));
 
     let _ = HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
-            .allowed_headers(vec![http::header::AUTHORIZATION, http::header::ACCEPT])
+            .allowed_methods(vec!["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"])
+            .allowed_headers(vec![
+                http::header::AUTHORIZATION,
+                http::header::ACCEPT,
+                http::header::CONTENT_TYPE,
+            ])
             .allowed_header("X-Blob-Content-Type")
+            .allowed_header("X-Blob-Visibility")
+            .allowed_header("X-Blob-Disposition")
+            .allowed_header("X-Blob-Response-Cache-Control")
+            .allowed_header("X-Blob-Response-Content-Language")
+            .allowed_header("X-Blob-Response-Content-Encoding")
+            .allowed_header("X-Blob-Response-Expires")
             .allowed_header("X-Blob-Access-Key")
+            .allowed_header("X-Blob-Max-Downloads")
+            .allowed_header("X-Blob-Publish-At")
+            .allowed_header("X-Blob-Tags")
+            .expose_any_header()
             .max_age(3600);
 
         App::new()
+            // Innermost, so the path has been normalised and the logger sees the request as it was sent
+            .wrap_fn(|mut req, srv| {
+                tenant::route_tenant(&mut req);
+                srv.call(req)
+            })
+            // Before tenant routing, so locked out clients can't make it look up tenants
+            .wrap_fn(rate_limit::limit_requests)
             .wrap(cors)
-            .wrap(Logger::default())
+            // Same as the default format, but with secrets removed from the request line
+            .wrap(
+                Logger::new(r#"%a "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+                    .custom_request_replace("request_line", redact::redact_request_line),
+            )
             .wrap(Compress::default())
             .wrap(NormalizePath::new(TrailingSlash::MergeOnly))
             .app_data(settings.clone())
             .app_data(path_manager.clone())
             .app_data(metadata_manager.clone())
+            .app_data(fulltext_index.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(bandwidth_limiter.clone())
             .service(web::resource("/").to(root_handler))
-            .service(bucket_get_file::get_file)
             .service(bucket::get_bucket_create)
+            .service(bucket::post_bucket_rotate_keys)
+            .service(bucket::patch_bucket_config)
             .service(bucket::put_bucket_upload)
             .service(bucket::bucket_verify)
             .service(bucket::delete_bucket_remove)
             .service(bucket::get_bucket_details)
+            .service(bucket::post_bucket_batch_details)
+            .service(bucket::patch_bucket_metadata)
+            .service(bucket_analytics::get_blob_analytics)
+            .service(bucket_analytics::get_bucket_analytics)
+            .service(bucket_analytics::get_bucket_top_downloads)
+            .service(bucket_analytics::get_bucket_egress)
+            .service(bucket_analytics::get_egress)
+            .service(bucket_usage::get_bucket_usage)
+            .service(bucket_usage::get_usage)
+            .service(bucket_search::get_search)
+            .service(bucket_search::get_bucket_search)
+            .configure(bucket_search::configure_text_search)
+            .service(bucket_users::post_login)
+            .service(bucket_users::post_logout)
+            .service(bucket_users::get_users)
+            .service(bucket_users::post_user_create)
+            .service(bucket_users::patch_user)
+            .service(bucket_users::delete_user)
+            .service(bucket_tenants::get_tenants)
+            .service(bucket_tenants::post_tenant_create)
+            .service(bucket_tenants::patch_tenant)
+            .service(bucket_tokens::post_token_create)
+            .service(bucket_tokens::post_token_revoke)
+            .service(bucket_presign::post_bucket_presign)
+            .service(bucket_share::post_bucket_share_create)
+            .service(bucket_share::get_bucket_shares)
+            .service(bucket_share::delete_bucket_share)
+            .service(bucket_share::get_share)
+            .service(bucket_share::post_share)
+            // Matches any two segment path, so must come after everything else
+            .service(bucket_get_file::get_file)
     })
     .bind(host)?
     .run()
     .await;
+
+    // Changes staged since the last periodic commit would otherwise be lost
+    committed_index.commit()?;
     Ok(())
 }
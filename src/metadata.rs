@@ -1,54 +1,1042 @@
-use crate::path::{BlobPath, PathDoesntExist, PathExists};
-use chrono::{DateTime, Utc};
-use rand::Rng;
+use crate::access_key::AccessKeyHash;
+use crate::analytics::{DailyStats, merge_daily_stats, parse_stats_key, stats_key};
+use crate::bucket_config::BucketConfig;
+use crate::disposition::Disposition;
+use crate::index::{IndexScan, SearchFilter, index_keys};
+use crate::path::{BlobPath, BucketPath, PathDoesntExist, PathExists};
+use crate::quota::{BlobQuotas, QuotaExceeded, QuotaScope, Usage};
+use crate::response_headers::ResponseHeaders;
+use crate::share::{ShareLink, ShareOutcome};
+use crate::tenant::Tenant;
+use crate::user::{Session, User, session_key};
+use crate::visibility::Visibility;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::Deserialize;
 use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sled::Transactional;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree, abort,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::ffi::OsStr;
 use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BlobMetadata {
     pub content_type: String,
-    pub access_key: String,
+    /// Content type detected from the content on upload, see [crate::sniff]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detected_content_type: Option<String>,
+    /// The content type given on upload disagrees with the content's magic bytes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub content_type_mismatch: bool,
+    /// The content type was detected rather than given by the uploader
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub content_type_detected: bool,
+    /// Hash of the key needed to modify or delete this blob
+    #[serde(default)]
+    pub access_key_hash: AccessKeyHash,
+    /// Plaintext key stored by older versions, replaced by [Self::access_key_hash] on startup
+    #[serde(
+        default,
+        rename = "access_key",
+        skip_serializing_if = "Option::is_none"
+    )]
+    legacy_access_key: Option<String>,
     pub deletion_date: Option<DateTime<Utc>>,
     #[serde(default)]
     pub created_at: Option<DateTime<Utc>>,
 
-    #[serde(default)]
+    /// Populated from [BlobCounters] when read, only ever deserialised to migrate old entries
+    #[serde(default, skip_serializing)]
     pub download_count: u32,
+
+    /// Number of downloads after which this blob is automatically soft-deleted
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+
+    /// Time before which this blob is only available to holders of its access key
+    #[serde(default)]
+    pub publish_at: Option<DateTime<Utc>>,
+
+    /// When this blob is meant to expire, only recorded and reported in its details
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+
+    /// Checksums of the content, calculated on upload
+    #[serde(default)]
+    pub checksums: Option<BlobChecksums>,
+
+    /// Populated from [BlobCounters] when read
+    #[serde(skip)]
+    pub last_downloaded: Option<DateTime<Utc>>,
+
+    /// Arbitrary key/value pairs given by the uploader, keys are always lowercase
+    #[serde(default)]
+    pub user_metadata: BTreeMap<String, String>,
+
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+
+    /// Size of the content in bytes
+    #[serde(default)]
+    pub size: Option<u64>,
+
+    /// Overrides the bucket's default visibility if set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+
+    /// Name of the file that was uploaded, sent as the filename of downloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+
+    /// How downloads are shown, inline if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disposition: Option<Disposition>,
+
+    /// Headers sent with downloads, on top of the bucket's defaults
+    #[serde(default, skip_serializing_if = "ResponseHeaders::is_empty")]
+    pub response_headers: ResponseHeaders,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlobChecksums {
+    pub sha1: String,
+    pub sha256: String,
+}
+
+impl BlobMetadata {
+    /// Has this blob been downloaded as many times as it is allowed to be
+    pub fn download_limit_reached(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max| self.download_count >= max)
+    }
+
+    /// If this blob can't be downloaded, get the reason why
+    /// A blob whose limit was lowered to its download count is used up even though it hasn't been deleted yet
+    pub fn unavailable_reason(&self) -> Option<DownloadOutcome> {
+        if self.download_limit_reached() {
+            return Some(DownloadOutcome::LimitReached);
+        }
+
+        if self.deletion_date.is_some() {
+            return Some(DownloadOutcome::Deleted);
+        }
+
+        None
+    }
+
+    /// Is this blob still waiting to be published
+    pub fn is_embargoed(&self) -> bool {
+        self.publish_at.is_some_and(|t| t > Utc::now())
+    }
+
+    /// Check if the given key matches the access key of this blob
+    pub fn check_access_key(&self, access_key: &str) -> bool {
+        self.access_key_hash.verify(access_key)
+    }
+
+    pub fn set_access_key(&mut self, access_key: &str) {
+        self.access_key_hash = AccessKeyHash::new(access_key);
+    }
+}
+
+/// Header prefix used to pass user metadata on upload and return it on download
+pub const USER_METADATA_HEADER_PREFIX: &str = "x-blob-meta-";
+
+/// Header containing a comma separated list of tags
+pub const TAGS_HEADER: &str = "X-Blob-Tags";
+
+/// Maximum number of user metadata entries on a blob
+const MAX_USER_METADATA: usize = 64;
+
+/// Maximum number of tags on a blob
+const MAX_TAGS: usize = 64;
+
+/// Maximum length of a single user metadata key, value or tag
+const MAX_USER_METADATA_LEN: usize = 1024;
+
+/// Version of the layout of the metadata DB, see [MetadataManager::migrate]
+/// Bump this whenever entries written by older versions need migrating
+const SCHEMA_VERSION: u64 = 1;
+
+/// Key the schema version is stored under in its tree
+const SCHEMA_VERSION_KEY: &[u8] = b"version";
+
+/// Check that user metadata can be sent back as headers, returns a description of the problem if it can't
+pub fn validate_user_metadata(
+    user_metadata: &BTreeMap<String, String>,
+    tags: &BTreeSet<String>,
+) -> Result<(), String> {
+    if user_metadata.len() > MAX_USER_METADATA {
+        return Err(format!(
+            "Too many metadata entries, max is {}",
+            MAX_USER_METADATA
+        ));
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(format!("Too many tags, max is {}", MAX_TAGS));
+    }
+
+    for (key, value) in user_metadata {
+        if key.is_empty()
+            || key.len() > MAX_USER_METADATA_LEN
+            || value.len() > MAX_USER_METADATA_LEN
+        {
+            return Err(format!("Invalid metadata length for {}", key));
+        }
+
+        let header_name = format!("{}{}", USER_METADATA_HEADER_PREFIX, key);
+        if HeaderName::from_lowercase(header_name.as_bytes()).is_err() {
+            return Err(format!("Invalid metadata key {}", key));
+        }
+
+        if HeaderValue::from_str(value).is_err() {
+            return Err(format!("Invalid metadata value for {}", key));
+        }
+    }
+
+    for tag in tags {
+        if tag.is_empty() || tag.len() > MAX_USER_METADATA_LEN || tag.contains(',') {
+            return Err(format!("Invalid tag {}", tag));
+        }
+
+        if HeaderValue::from_str(tag).is_err() {
+            return Err(format!("Invalid tag {}", tag));
+        }
+    }
+
+    Ok(())
+}
+
+/// The result of trying to record a download of a blob
+pub enum DownloadOutcome {
+    /// The download can go ahead, holds the metadata after the download was counted
+    Allowed(Box<BlobMetadata>),
+    /// The blob has been soft-deleted
+    Deleted,
+    /// The blob has been downloaded the maximum number of times and is now deleted
+    LimitReached,
 }
 
 impl Default for BlobMetadata {
     fn default() -> Self {
-        let key = (0..48)
-            .map(|_| rand::rng().random_range('A'..='Z'))
-            .collect();
         Self {
             content_type: "text".to_string(),
-            access_key: key,
+            detected_content_type: None,
+            content_type_mismatch: false,
+            content_type_detected: false,
+            access_key_hash: AccessKeyHash::default(),
+            legacy_access_key: None,
             deletion_date: None,
             created_at: Some(Utc::now()),
             download_count: 0,
+            max_downloads: None,
+            publish_at: None,
+            expires_at: None,
+            checksums: None,
+            last_downloaded: None,
+            user_metadata: BTreeMap::new(),
+            tags: BTreeSet::new(),
+            size: None,
+            visibility: None,
+            original_filename: None,
+            disposition: None,
+            response_headers: ResponseHeaders::default(),
         }
     }
 }
 
+/// Counters that are updated too often to be stored alongside the rest of the metadata
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlobCounters {
+    pub download_count: u32,
+    #[serde(default)]
+    pub last_downloaded: Option<DateTime<Utc>>,
+}
+
+/// Errors that can abort a metadata transaction
+#[derive(Debug)]
+enum TransactionAbort<E> {
+    /// The caller chose to abort the update
+    User(E),
+    /// The blob has no metadata
+    Missing,
+    /// Stored metadata couldn't be (de)serialised
+    Json(serde_json::Error),
+}
+
+impl<E> From<serde_json::Error> for TransactionAbort<E> {
+    fn from(e: serde_json::Error) -> Self {
+        TransactionAbort::Json(e)
+    }
+}
+
+type TransactionResult<T, E> = ConflictableTransactionResult<T, TransactionAbort<E>>;
+
+/// Convert the result of a transaction into a nested result, where the inner error is the one chosen by the caller
+fn flatten_transaction<T, E>(
+    res: Result<T, TransactionError<TransactionAbort<E>>>,
+) -> anyhow::Result<Result<T, E>> {
+    match res {
+        Ok(t) => Ok(Ok(t)),
+        Err(TransactionError::Abort(TransactionAbort::User(e))) => Ok(Err(e)),
+        Err(TransactionError::Abort(TransactionAbort::Missing)) => {
+            Err(anyhow::anyhow!("Tried to update metadata for missing blob"))
+        }
+        Err(TransactionError::Abort(TransactionAbort::Json(e))) => Err(e.into()),
+        Err(TransactionError::Storage(e)) => Err(e.into()),
+    }
+}
+
+fn read_json<T: DeserializeOwned, E>(data: &[u8]) -> TransactionResult<T, E> {
+    serde_json::from_slice(data).map_err(|e| ConflictableTransactionError::Abort(e.into()))
+}
+
+fn write_json<T: Serialize, E>(value: &T) -> TransactionResult<Vec<u8>, E> {
+    serde_json::to_vec(value).map_err(|e| ConflictableTransactionError::Abort(e.into()))
+}
+
+/// Bring the search index in line with a blob's metadata changing from having `old` index keys to having `new` ones
+fn update_index<E>(
+    index_tree: &TransactionalTree,
+    old: &BTreeSet<Vec<u8>>,
+    new: &BTreeSet<Vec<u8>>,
+) -> TransactionResult<(), E> {
+    for key in old.difference(new) {
+        index_tree.remove(key.as_slice())?;
+    }
+    for key in new.difference(old) {
+        index_tree.insert(key.as_slice(), &[])?;
+    }
+    Ok(())
+}
+
+/// Soft-delete a blob as part of a transaction over the metadata and index trees
+fn soft_delete<E>(
+    meta_tree: &TransactionalTree,
+    index_tree: &TransactionalTree,
+    key: &[u8],
+    meta: &mut BlobMetadata,
+) -> TransactionResult<(), E> {
+    let old_keys = index_keys(key, meta);
+    meta.deletion_date = Some(Utc::now());
+    meta_tree.insert(key, write_json(meta)?)?;
+    update_index(index_tree, &old_keys, &index_keys(key, meta))
+}
+
+/// Count a download of the blob at `key` if it's still available, see [MetadataManager::record_download]
+fn count_download<E>(
+    meta_tree: &TransactionalTree,
+    counter_tree: &TransactionalTree,
+    index_tree: &TransactionalTree,
+    key: &[u8],
+) -> TransactionResult<DownloadOutcome, E> {
+    let mut meta: BlobMetadata = match meta_tree.get(key)? {
+        Some(data) => read_json(&data)?,
+        None => return abort(TransactionAbort::Missing),
+    };
+    let mut counters: BlobCounters = match counter_tree.get(key)? {
+        Some(data) => read_json(&data)?,
+        None => BlobCounters::default(),
+    };
+    meta.download_count = counters.download_count;
+
+    if let Some(outcome) = meta.unavailable_reason() {
+        // Lowering the limit doesn't delete the blob, so the first download after does instead
+        if meta.deletion_date.is_none() {
+            tracing::info!("Download limit lowered past download count, removing blob");
+            soft_delete(meta_tree, index_tree, key, &mut meta)?;
+        }
+        return Ok(outcome);
+    }
+
+    counters.download_count += 1;
+    counters.last_downloaded = Some(Utc::now());
+    meta.download_count = counters.download_count;
+    meta.last_downloaded = counters.last_downloaded;
+    counter_tree.insert(key, write_json(&counters)?)?;
+
+    if meta.download_limit_reached() {
+        tracing::info!("Download limit reached, removing blob");
+        soft_delete(meta_tree, index_tree, key, &mut meta)?;
+    }
+
+    Ok(DownloadOutcome::Allowed(Box::new(meta)))
+}
+
+/// Every entry of a tree of [DailyStats] keyed by [stats_key] whose key starts with `prefix`, between `from` and `to`
+/// inclusive, along with the key it's for and the day
+fn scan_daily_stats(
+    tree: &sled::Tree,
+    prefix: &[u8],
+    from: NaiveDate,
+    to: NaiveDate,
+) -> anyhow::Result<Vec<(Vec<u8>, NaiveDate, DailyStats)>> {
+    let mut out = Vec::new();
+    for entry in tree.scan_prefix(prefix) {
+        let (key, value) = entry?;
+        let Some((path, day)) = parse_stats_key(&key) else {
+            tracing::warn!("Invalid analytics key {}", String::from_utf8_lossy(&key));
+            continue;
+        };
+
+        if day >= from && day <= to {
+            out.push((path.to_vec(), day, DailyStats::from_bytes(&value)));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Keys of the [Usage] a blob counts towards, its bucket and its namespace
+/// Blobs are stored directly in their bucket, which is directly in the root of its namespace
+fn usage_keys(blob_key: &[u8]) -> Option<[Vec<u8>; 2]> {
+    let bucket = Path::new(OsStr::from_bytes(blob_key)).parent()?;
+    let namespace = bucket.parent()?;
+    Some([
+        bucket.as_os_str().as_bytes().to_vec(),
+        namespace.as_os_str().as_bytes().to_vec(),
+    ])
+}
+
 pub struct MetadataManager {
     sled: sled::Db,
+
+    /// Per-blob [BlobCounters], kept apart from the metadata so hot counters don't rewrite the whole entry
+    counters: sled::Tree,
+
+    /// Per-blob, per-day [DailyStats]
+    analytics: sled::Tree,
+
+    /// Secondary indexes used for searching, see [crate::index]
+    index: sled::Tree,
+
+    /// [BucketConfig] for each bucket, keyed by bucket path
+    buckets: sled::Tree,
+
+    /// Ids of revoked API tokens, mapped to when they expire so they can be cleaned up
+    revoked_tokens: sled::Tree,
+
+    /// [ShareLink]s, keyed by their id
+    shares: sled::Tree,
+
+    /// [User]s, keyed by username
+    users: sled::Tree,
+
+    /// Logged in [Session]s, keyed by the hash of their token
+    sessions: sled::Tree,
+
+    /// [Tenant]s, keyed by name
+    tenants: sled::Tree,
+
+    /// Names of tenants, keyed by the host names that route to them
+    tenant_hosts: sled::Tree,
+
+    /// The [SCHEMA_VERSION] the DB has been migrated to
+    schema: sled::Tree,
+
+    /// [Usage] of every bucket and namespace, keyed by their path
+    usage: sled::Tree,
+
+    /// Per-bucket, per-day [DailyStats] of the bytes actually sent by downloads
+    egress: sled::Tree,
 }
 
 impl MetadataManager {
     pub fn new() -> anyhow::Result<Self> {
-        let sled = sled::open("./storage_root/metadata.db")?;
+        Self::open(sled::open("./storage_root/metadata.db")?)
+    }
+
+    /// A manager over a DB that is thrown away when dropped
+    #[cfg(test)]
+    pub fn temporary() -> anyhow::Result<Self> {
+        Self::open(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn open(sled: sled::Db) -> anyhow::Result<Self> {
+        let counters = sled.open_tree("counters")?;
+        let analytics = sled.open_tree("analytics")?;
+        analytics.set_merge_operator(merge_daily_stats);
+        let index = sled.open_tree("index")?;
+        let buckets = sled.open_tree("buckets")?;
+        let revoked_tokens = sled.open_tree("revoked_tokens")?;
+        let shares = sled.open_tree("shares")?;
+        let users = sled.open_tree("users")?;
+        let sessions = sled.open_tree("sessions")?;
+        let tenants = sled.open_tree("tenants")?;
+        let tenant_hosts = sled.open_tree("tenant_hosts")?;
+        let schema = sled.open_tree("schema")?;
+        let usage = sled.open_tree("usage")?;
+        let egress = sled.open_tree("egress")?;
+        egress.set_merge_operator(merge_daily_stats);
+
+        let manager = Self {
+            sled,
+            counters,
+            analytics,
+            index,
+            buckets,
+            revoked_tokens,
+            shares,
+            users,
+            sessions,
+            tenants,
+            tenant_hosts,
+            schema,
+            usage,
+            egress,
+        };
+        manager.migrate()?;
+        manager.purge_revoked_tokens()?;
+        manager.purge_expired_sessions()?;
+
+        Ok(manager)
+    }
+
+    /// Bring entries written by older versions up to date and rebuild the search index
+    /// - Download counts that were stored inline in the metadata are moved into the counters tree
+    /// - Blobs without a recorded size get it from the filesystem
+    /// - Plaintext access keys are replaced with hashes
+    ///
+    /// Storage usage is recounted too, so it is correct for blobs stored before it was tracked, and the host names of
+    /// tenants are indexed
+    ///
+    /// This only runs once per [SCHEMA_VERSION]. Entries that can't be read are logged and left as they are, rather
+    /// than stopping the server from starting
+    fn migrate(&self) -> anyhow::Result<()> {
+        let version = match self.schema.get(SCHEMA_VERSION_KEY)? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => 0,
+        };
+        if version >= SCHEMA_VERSION {
+            return Ok(());
+        }
+
+        let _span = tracing::info_span!("migrate_metadata", from = version).entered();
+
+        self.tenant_hosts.clear()?;
+        for entry in self.tenants.iter() {
+            let (name, data) = entry?;
+            let tenant: Tenant = match serde_json::from_slice(&data) {
+                Ok(t) => t,
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping unreadable tenant {}: {}",
+                        String::from_utf8_lossy(&name),
+                        e
+                    );
+                    continue;
+                }
+            };
+            for host in &tenant.hosts {
+                self.tenant_hosts
+                    .insert(host.as_bytes(), tenant.name.as_bytes())?;
+            }
+        }
+
+        self.index.clear()?;
+        let mut usage: BTreeMap<Vec<u8>, Usage> = BTreeMap::new();
+
+        for entry in self.sled.iter() {
+            let (key, data) = entry?;
+            let mut meta: BlobMetadata = match serde_json::from_slice(&data) {
+                Ok(m) => m,
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping unreadable metadata of {}: {}",
+                        String::from_utf8_lossy(&key),
+                        e
+                    );
+                    continue;
+                }
+            };
+            let mut changed = false;
+
+            if meta.size.is_none()
+                && let Ok(fs_meta) = std::fs::metadata(OsStr::from_bytes(&key))
+            {
+                meta.size = Some(fs_meta.len());
+                changed = true;
+            }
+
+            if let Some(access_key) = meta.legacy_access_key.take() {
+                tracing::info!("Hashing access key for {}", String::from_utf8_lossy(&key));
+                meta.access_key_hash = AccessKeyHash::new_unchecked(&access_key)?;
+                changed = true;
+            }
+
+            if changed {
+                self.sled.insert(&key, serde_json::to_vec(&meta)?)?;
+            }
+
+            for index_key in index_keys(&key, &meta) {
+                self.index.insert(index_key, &[])?;
+            }
 
-        Ok(Self { sled })
+            if let Some(keys) = usage_keys(&key) {
+                for usage_key in keys {
+                    let entry = usage.entry(usage_key).or_default();
+                    *entry = entry.with_blob(meta.size.unwrap_or_default());
+                }
+            }
+
+            if meta.download_count > 0 && !self.counters.contains_key(&key)? {
+                tracing::info!(
+                    "Migrating download count for {}",
+                    String::from_utf8_lossy(&key)
+                );
+                let counters = BlobCounters {
+                    download_count: meta.download_count,
+                    last_downloaded: None,
+                };
+                self.counters.insert(&key, serde_json::to_vec(&counters)?)?;
+            }
+        }
+
+        self.usage.clear()?;
+        for (key, usage) in usage {
+            self.usage.insert(key, serde_json::to_vec(&usage)?)?;
+        }
+
+        self.schema
+            .insert(SCHEMA_VERSION_KEY, serde_json::to_vec(&SCHEMA_VERSION)?)?;
+        tracing::info!("Migrated metadata to version {}", SCHEMA_VERSION);
+        Ok(())
+    }
+
+    /// The [Usage] of the bucket or namespace at `path`
+    pub fn get_usage(&self, path: &Path) -> anyhow::Result<Usage> {
+        match self.usage.get(path.as_os_str().as_bytes())? {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Ok(Usage::default()),
+        }
+    }
+
+    pub fn get_bucket_config(
+        &self,
+        bucket: &BucketPath<PathExists>,
+    ) -> anyhow::Result<Option<BucketConfig>> {
+        match self.buckets.get(bucket.as_os_str().as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the config of a bucket, replacing any existing config
+    pub fn save_bucket_config<T>(
+        &self,
+        bucket: &BucketPath<T>,
+        config: &BucketConfig,
+    ) -> anyhow::Result<()> {
+        self.buckets
+            .insert(bucket.as_os_str().as_bytes(), serde_json::to_vec(config)?)?;
+        Ok(())
+    }
+
+    /// Atomically apply `f` to the config of a bucket, a default config is given to `f` if the bucket has none
+    pub fn update_bucket_config<T>(
+        &self,
+        bucket: &BucketPath<PathExists>,
+        f: impl Fn(&mut BucketConfig, bool) -> T,
+    ) -> anyhow::Result<T> {
+        let key = bucket.as_os_str().as_bytes();
+
+        let res = self.buckets.transaction(|tree| {
+            let (mut config, exists) = match tree.get(key)? {
+                Some(data) => (read_json(&data)?, true),
+                None => (BucketConfig::default(), false),
+            };
+
+            let res = f(&mut config, exists);
+            tree.insert(key, write_json(&config)?)?;
+            Ok(res)
+        });
+
+        let Ok(res) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(res)
+    }
+
+    pub fn revoke_token(&self, id: &str, expires_at: DateTime<Utc>) -> anyhow::Result<()> {
+        self.revoked_tokens
+            .insert(id.as_bytes(), serde_json::to_vec(&expires_at)?)?;
+        Ok(())
+    }
+
+    pub fn is_token_revoked(&self, id: &str) -> anyhow::Result<bool> {
+        Ok(self.revoked_tokens.contains_key(id.as_bytes())?)
+    }
+
+    /// Forget revoked tokens that have expired, as they can't be used anyway
+    fn purge_revoked_tokens(&self) -> anyhow::Result<()> {
+        for entry in self.revoked_tokens.iter() {
+            let (id, data) = entry?;
+            let expires_at: DateTime<Utc> = serde_json::from_slice(&data)?;
+            if expires_at <= Utc::now() {
+                self.revoked_tokens.remove(id)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a new user, returning false if the username is taken
+    pub fn create_user(&self, user: &User) -> anyhow::Result<bool> {
+        Ok(self
+            .users
+            .compare_and_swap(
+                user.username.as_bytes(),
+                None as Option<&[u8]>,
+                Some(serde_json::to_vec(user)?),
+            )?
+            .is_ok())
+    }
+
+    pub fn get_user(&self, username: &str) -> anyhow::Result<Option<User>> {
+        match self.users.get(username.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_users(&self) -> anyhow::Result<Vec<User>> {
+        self.users
+            .iter()
+            .map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+            .collect()
+    }
+
+    /// Update a user with the given function, returning `None` if there is no such user
+    pub fn update_user<T, E>(
+        &self,
+        username: &str,
+        f: impl Fn(&mut User) -> Result<T, E>,
+    ) -> anyhow::Result<Option<Result<T, E>>> {
+        let res = self.users.transaction(|tree| {
+            let mut user: User = match tree.get(username.as_bytes())? {
+                Some(data) => read_json(&data)?,
+                None => return Ok(None),
+            };
+
+            let res = match f(&mut user) {
+                Ok(t) => t,
+                Err(e) => return abort(TransactionAbort::User(e)),
+            };
+
+            tree.insert(username.as_bytes(), write_json(&user)?)?;
+            Ok(Some(res))
+        });
+
+        Ok(match flatten_transaction(res)? {
+            Ok(Some(t)) => Some(Ok(t)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// Remove a user and log out all of their sessions, returning if they existed
+    pub fn remove_user(&self, username: &str) -> anyhow::Result<bool> {
+        let existed = self.users.remove(username.as_bytes())?.is_some();
+        self.remove_user_sessions(username, None)?;
+        Ok(existed)
+    }
+
+    /// Store a new tenant, returning false if the name is taken
+    pub fn create_tenant(&self, tenant: &Tenant) -> anyhow::Result<bool> {
+        let res = (&self.tenants, &self.tenant_hosts).transaction(|(tenant_tree, host_tree)| {
+            if tenant_tree.get(tenant.name.as_bytes())?.is_some() {
+                return Ok(false);
+            }
+
+            tenant_tree.insert(tenant.name.as_bytes(), write_json(tenant)?)?;
+            for host in &tenant.hosts {
+                host_tree.insert(host.as_bytes(), tenant.name.as_bytes())?;
+            }
+            Ok(true)
+        });
+
+        let Ok(created) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(created)
+    }
+
+    pub fn get_tenant(&self, name: &str) -> anyhow::Result<Option<Tenant>> {
+        match self.tenants.get(name.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn list_tenants(&self) -> anyhow::Result<Vec<Tenant>> {
+        self.tenants
+            .iter()
+            .map(|entry| Ok(serde_json::from_slice(&entry?.1)?))
+            .collect()
+    }
+
+    /// Find the tenant that a host name routes to
+    pub fn get_tenant_by_host(&self, host: &str) -> anyhow::Result<Option<Tenant>> {
+        match self.tenant_hosts.get(host.as_bytes())? {
+            Some(name) => self.get_tenant(&String::from_utf8_lossy(&name)),
+            None => Ok(None),
+        }
     }
 
-    pub fn get_metadata(&self, blob_path: &BlobPath<PathExists>, create_if_missing: bool) -> anyhow::Result<BlobMetadata> {
+    /// Update a tenant with the given function, returning `None` if there is no such tenant
+    pub fn update_tenant<T, E>(
+        &self,
+        name: &str,
+        f: impl Fn(&mut Tenant) -> Result<T, E>,
+    ) -> anyhow::Result<Option<Result<T, E>>> {
+        let res = (&self.tenants, &self.tenant_hosts).transaction(|(tenant_tree, host_tree)| {
+            let mut tenant: Tenant = match tenant_tree.get(name.as_bytes())? {
+                Some(data) => read_json(&data)?,
+                None => return Ok(None),
+            };
+            let old_hosts = tenant.hosts.clone();
+
+            let res = match f(&mut tenant) {
+                Ok(t) => t,
+                Err(e) => return abort(TransactionAbort::User(e)),
+            };
+
+            tenant_tree.insert(name.as_bytes(), write_json(&tenant)?)?;
+            for host in old_hosts.difference(&tenant.hosts) {
+                host_tree.remove(host.as_bytes())?;
+            }
+            for host in &tenant.hosts {
+                host_tree.insert(host.as_bytes(), name.as_bytes())?;
+            }
+            Ok(Some(res))
+        });
+
+        Ok(match flatten_transaction(res)? {
+            Ok(Some(t)) => Some(Ok(t)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    pub fn create_session(&self, token: &str, session: &Session) -> anyhow::Result<()> {
+        self.sessions
+            .insert(session_key(token), serde_json::to_vec(session)?)?;
+        Ok(())
+    }
+
+    /// Get the session for a token, if it exists and hasn't expired
+    pub fn get_session(&self, token: &str) -> anyhow::Result<Option<Session>> {
+        let Some(data) = self.sessions.get(session_key(token))? else {
+            return Ok(None);
+        };
+
+        let session: Session = serde_json::from_slice(&data)?;
+        if session.expires_at <= Utc::now() {
+            self.sessions.remove(session_key(token))?;
+            return Ok(None);
+        }
+
+        Ok(Some(session))
+    }
+
+    pub fn remove_session(&self, token: &str) -> anyhow::Result<bool> {
+        Ok(self.sessions.remove(session_key(token))?.is_some())
+    }
+
+    /// Log out every session of a user, except the one with the token `keep`
+    pub fn remove_user_sessions(&self, username: &str, keep: Option<&str>) -> anyhow::Result<()> {
+        let keep = keep.map(session_key);
+        for entry in self.sessions.iter() {
+            let (key, data) = entry?;
+            let session: Session = serde_json::from_slice(&data)?;
+            if session.username == username && keep.as_ref().map(String::as_bytes) != Some(&*key) {
+                self.sessions.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn purge_expired_sessions(&self) -> anyhow::Result<()> {
+        for entry in self.sessions.iter() {
+            let (key, data) = entry?;
+            let session: Session = serde_json::from_slice(&data)?;
+            if session.expires_at <= Utc::now() {
+                self.sessions.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a new share link, fails if one with the same id exists
+    pub fn create_share(&self, share: &ShareLink) -> anyhow::Result<()> {
+        self.shares
+            .compare_and_swap(
+                share.id.as_bytes(),
+                None as Option<&[u8]>,
+                Some(serde_json::to_vec(share)?),
+            )?
+            .map_err(|_| anyhow::anyhow!("Share {} already exists", share.id))
+    }
+
+    pub fn get_share(&self, id: &str) -> anyhow::Result<Option<ShareLink>> {
+        match self.shares.get(id.as_bytes())? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// All the share links of a blob
+    pub fn list_shares(&self, blob_path: &BlobPath<PathExists>) -> anyhow::Result<Vec<ShareLink>> {
+        let key = blob_path.as_os_str().as_bytes();
+        let mut shares = Vec::new();
+        for entry in self.shares.iter() {
+            let (_, data) = entry?;
+            let share: ShareLink = serde_json::from_slice(&data)?;
+            if share.blob_key == key {
+                shares.push(share);
+            }
+        }
+        Ok(shares)
+    }
+
+    /// Remove a share link of the given blob, returning if it existed
+    pub fn remove_share(&self, blob_path: &BlobPath<PathExists>, id: &str) -> anyhow::Result<bool> {
+        let key = blob_path.as_os_str().as_bytes();
+        let res = self.shares.transaction(|tree| {
+            let Some(data) = tree.get(id.as_bytes())? else {
+                return Ok(false);
+            };
+            let share: ShareLink = read_json(&data)?;
+            if share.blob_key != key {
+                return Ok(false);
+            }
+            tree.remove(id.as_bytes())?;
+            Ok(true)
+        });
+
+        let Ok(removed) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(removed)
+    }
+
+    /// Count a download of the given blob through a share link, if the link is still usable
+    /// The blob's download is counted in the same transaction, and the link's only if the blob's was allowed, so a
+    /// blob that can't be served never uses up the link
+    pub fn record_share_download(
+        &self,
+        id: &str,
+        blob_path: &BlobPath<PathExists>,
+    ) -> anyhow::Result<ShareOutcome> {
+        let key = blob_path.as_os_str().as_bytes();
+
+        let res = (&*self.sled, &self.counters, &self.index, &self.shares).transaction(
+            |(meta_tree, counter_tree, index_tree, share_tree)| {
+                let mut share: ShareLink = match share_tree.get(id.as_bytes())? {
+                    Some(data) => read_json(&data)?,
+                    None => return Ok(ShareOutcome::Missing),
+                };
+
+                if share.is_expired() {
+                    return Ok(ShareOutcome::Expired);
+                }
+
+                if share.download_limit_reached() {
+                    return Ok(ShareOutcome::LimitReached);
+                }
+
+                let outcome = count_download(meta_tree, counter_tree, index_tree, key)?;
+                if matches!(outcome, DownloadOutcome::Allowed(_)) {
+                    share.download_count += 1;
+                    share_tree.insert(id.as_bytes(), write_json(&share)?)?;
+                }
+                Ok(ShareOutcome::Blob(outcome))
+            },
+        );
+
+        let Ok(outcome) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(outcome)
+    }
+
+    /// Search for blobs whose path starts with `prefix` and that match `filter`, returning at most `limit` results
+    /// Blobs are only returned if `visible` is true for them, others don't count towards the limit
+    pub fn search(
+        &self,
+        prefix: &[u8],
+        filter: &SearchFilter,
+        limit: usize,
+        visible: impl Fn(&[u8], &BlobMetadata) -> bool,
+    ) -> anyhow::Result<Vec<(Vec<u8>, BlobMetadata)>> {
+        let _span = tracing::info_span!("search_metadata").entered();
+
+        let scan = filter.plan();
+        let candidates = match &scan {
+            IndexScan::Prefix(p) => self.index.scan_prefix(p),
+            IndexScan::Range(start, end) => {
+                self.index.range::<Vec<u8>, _>((start.clone(), end.clone()))
+            }
+            IndexScan::All => self.sled.scan_prefix(prefix),
+        };
+
+        let mut out = Vec::new();
+        for entry in candidates {
+            if out.len() >= limit {
+                break;
+            }
+
+            let (index_key, _) = entry?;
+            let Some(blob_key) = scan.blob_key(&index_key) else {
+                continue;
+            };
+
+            if !blob_key.starts_with(prefix) {
+                continue;
+            }
+
+            if let Some(meta) = self.get_metadata_by_key(blob_key)?
+                && filter.matches(&meta)
+                && visible(blob_key, &meta)
+            {
+                out.push((blob_key.to_vec(), meta));
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Get the metadata of a blob from its raw key, as returned by [Self::search]
+    pub fn get_metadata_by_key(&self, key: &[u8]) -> anyhow::Result<Option<BlobMetadata>> {
+        let Some(data) = self.sled.get(key)? else {
+            return Ok(None);
+        };
+
+        let mut meta: BlobMetadata = serde_json::from_slice(&data)?;
+        let counters = self.get_counters(key)?;
+        meta.download_count = counters.download_count;
+        meta.last_downloaded = counters.last_downloaded;
+
+        Ok(Some(meta))
+    }
+
+    fn get_counters(&self, key: &[u8]) -> anyhow::Result<BlobCounters> {
+        Ok(match self.counters.get(key)? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => BlobCounters::default(),
+        })
+    }
+
+    pub fn get_metadata(
+        &self,
+        blob_path: &BlobPath<PathExists>,
+        create_if_missing: bool,
+    ) -> anyhow::Result<BlobMetadata> {
         let _span = tracing::info_span!("get_metadata").entered();
 
-        let meta = self.sled.get(blob_path.as_os_str().as_bytes())?;
+        let key = blob_path.as_os_str().as_bytes();
+        let meta = self.sled.get(key)?;
 
-        let meta = match meta {
+        let mut meta: BlobMetadata = match meta {
             Some(data) => {
                 let data_str = String::from_utf8(data.to_vec())?;
                 serde_json::from_str(&data_str)?
@@ -57,42 +1045,595 @@ impl MetadataManager {
                 if create_if_missing {
                     BlobMetadata::default()
                 } else {
-                    return Err(anyhow::anyhow!("Tried to get metadata for missing blob, but can't create"));
+                    return Err(anyhow::anyhow!(
+                        "Tried to get metadata for missing blob, but can't create"
+                    ));
                 }
-            },
+            }
         };
 
-        tracing::info!("Got meta: {:?}", meta);
+        let counters = self.get_counters(key)?;
+        meta.download_count = counters.download_count;
+        meta.last_downloaded = counters.last_downloaded;
 
         Ok(meta)
     }
 
-    pub fn remove_metadata(&self, blob_path: &BlobPath<PathExists>) -> anyhow::Result<()> {
-        self.sled.remove(blob_path.as_os_str().as_bytes())?;
-        Ok(())
+    /// Atomically count a download of the given blob
+    /// If this download uses up the last allowed download, the blob is soft-deleted in the same transaction
+    /// so concurrent downloads can never exceed the limit
+    pub fn record_download(
+        &self,
+        blob_path: &BlobPath<PathExists>,
+    ) -> anyhow::Result<DownloadOutcome> {
+        let _span = tracing::info_span!("record_download").entered();
+
+        let key = blob_path.as_os_str().as_bytes();
+
+        let res = (&*self.sled, &self.counters, &self.index).transaction(
+            |(meta_tree, counter_tree, index_tree)| {
+                count_download(meta_tree, counter_tree, index_tree, key)
+            },
+        );
+
+        let Ok(outcome) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(outcome)
     }
 
-    pub fn create_metadata(
+    /// Add a download of `bytes` bytes to today's stats for the given blob
+    pub fn record_daily_download(
         &self,
-        blob_path: &BlobPath<PathDoesntExist>,
-        metadata: &BlobMetadata,
+        blob_path: &BlobPath<PathExists>,
+        bytes: u64,
     ) -> anyhow::Result<()> {
-        let meta_json = serde_json::to_string(&metadata)?;
-        assert!(!self.sled.contains_key(blob_path.as_os_str().as_bytes())?);
-        self.sled
-            .insert(blob_path.as_os_str().as_bytes(), meta_json.as_bytes())?;
+        let key = stats_key(blob_path.as_os_str().as_bytes(), Utc::now().date_naive());
+        let stats = DailyStats {
+            downloads: 1,
+            bytes,
+        };
+        self.analytics.merge(key, stats.to_bytes())?;
+        Ok(())
+    }
+
+    /// Get the daily stats of every blob whose path starts with `prefix`, between `from` and `to` inclusive
+    /// Returns the path of each blob along with the day and its stats
+    pub fn get_daily_stats(
+        &self,
+        prefix: &[u8],
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<Vec<(Vec<u8>, NaiveDate, DailyStats)>> {
+        let _span = tracing::info_span!("get_daily_stats").entered();
+        scan_daily_stats(&self.analytics, prefix, from, to)
+    }
+
+    /// Count the bytes sent by a download against its bucket
+    /// Unlike [Self::record_daily_download], this is what was actually sent, so downloads cut short count partly
+    pub fn record_egress(&self, bucket_key: &[u8], bytes: u64) -> anyhow::Result<()> {
+        let key = stats_key(bucket_key, Utc::now().date_naive());
+        let stats = DailyStats {
+            downloads: 1,
+            bytes,
+        };
+        self.egress.merge(key, stats.to_bytes())?;
         Ok(())
     }
 
-    pub fn save_metadata(
+    /// Get the daily egress of every bucket whose path starts with `prefix`, between `from` and `to` inclusive
+    pub fn get_egress(
+        &self,
+        prefix: &[u8],
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<Vec<(Vec<u8>, NaiveDate, DailyStats)>> {
+        let _span = tracing::info_span!("get_egress").entered();
+        scan_daily_stats(&self.egress, prefix, from, to)
+    }
+
+    /// Atomically apply `f` to the metadata of the given blob
+    /// `f` may be called more than once if the metadata is changed concurrently, if it returns an error nothing is saved
+    /// and the error is returned in the inner result
+    /// `f` mustn't read the DB outside the transaction, such as through [crate::auth::Authorizer] checks other than
+    /// [crate::auth::Authorizer::has_blob_key], as sled never returns from that. Check those before updating
+    pub fn update_metadata<T, E>(
         &self,
         blob_path: &BlobPath<PathExists>,
+        f: impl Fn(&mut BlobMetadata) -> Result<T, E>,
+    ) -> anyhow::Result<Result<T, E>> {
+        let _span = tracing::info_span!("update_metadata").entered();
+
+        let key = blob_path.as_os_str().as_bytes();
+
+        let res = (&*self.sled, &self.counters, &self.index).transaction(
+            |(meta_tree, counter_tree, index_tree)| {
+                let mut meta: BlobMetadata = match meta_tree.get(key)? {
+                    Some(data) => read_json(&data)?,
+                    None => return abort(TransactionAbort::Missing),
+                };
+                let old_keys = index_keys(key, &meta);
+                if let Some(data) = counter_tree.get(key)? {
+                    let counters: BlobCounters = read_json(&data)?;
+                    meta.download_count = counters.download_count;
+                    meta.last_downloaded = counters.last_downloaded;
+                }
+
+                let res = match f(&mut meta) {
+                    Ok(t) => t,
+                    Err(e) => return abort(TransactionAbort::User(e)),
+                };
+
+                meta_tree.insert(key, write_json(&meta)?)?;
+                update_index(index_tree, &old_keys, &index_keys(key, &meta))?;
+
+                Ok(res)
+            },
+        );
+
+        flatten_transaction(res)
+    }
+
+    /// Remove the metadata of a blob, but only if it has been soft-deleted
+    /// Returns false if the blob is not deleted, in which case nothing is removed
+    pub fn remove_deleted_metadata(
+        &self,
+        blob_path: &BlobPath<PathExists>,
+    ) -> anyhow::Result<bool> {
+        let key = blob_path.as_os_str().as_bytes();
+
+        let res = (&*self.sled, &self.counters, &self.index, &self.usage).transaction(
+            |(meta_tree, counter_tree, index_tree, usage_tree)| {
+                let meta: BlobMetadata = match meta_tree.get(key)? {
+                    Some(data) => read_json(&data)?,
+                    None => return abort(TransactionAbort::Missing),
+                };
+
+                if meta.deletion_date.is_none() {
+                    return Ok(false);
+                }
+
+                meta_tree.remove(key)?;
+                counter_tree.remove(key)?;
+                update_index(index_tree, &index_keys(key, &meta), &BTreeSet::new())?;
+
+                for usage_key in usage_keys(key).into_iter().flatten() {
+                    let usage: Usage = match usage_tree.get(&usage_key)? {
+                        Some(data) => read_json(&data)?,
+                        None => Usage::default(),
+                    };
+                    let usage = usage.without_blob(meta.size.unwrap_or_default());
+                    usage_tree.insert(usage_key, write_json(&usage)?)?;
+                }
+                Ok(true)
+            },
+        );
+
+        let Ok(removed) = flatten_transaction::<_, Infallible>(res)?;
+        Ok(removed)
+    }
+
+    /// Store the metadata for a new blob, fails if the blob already has metadata
+    /// The blob is counted towards the usage of its bucket and namespace, unless that would go past `quotas`. Returns
+    /// the new usage of the bucket and of the namespace
+    pub fn create_metadata(
+        &self,
+        blob_path: &BlobPath<PathDoesntExist>,
         metadata: &BlobMetadata,
-    ) -> anyhow::Result<()> {
-        self.sled.insert(
-            blob_path.as_os_str().as_bytes(),
-            serde_json::to_string(&metadata)?.as_bytes(),
-        )?;
-        Ok(())
+        quotas: &BlobQuotas,
+    ) -> anyhow::Result<Result<(Usage, Usage), QuotaExceeded>> {
+        let key = blob_path.as_os_str().as_bytes();
+        let Some([bucket_key, namespace_key]) = usage_keys(key) else {
+            return Err(anyhow::anyhow!("Blob is not in a bucket"));
+        };
+
+        let res = (&*self.sled, &self.counters, &self.index, &self.usage).transaction(
+            |(meta_tree, counter_tree, index_tree, usage_tree)| {
+                if meta_tree.get(key)?.is_some() {
+                    return abort(TransactionAbort::User(None));
+                }
+
+                let mut new_usage = [Usage::default(); 2];
+                for (i, (usage_key, quota, scope)) in [
+                    (&bucket_key, quotas.bucket, QuotaScope::Bucket),
+                    (&namespace_key, quotas.namespace, QuotaScope::Tenant),
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    let usage: Usage = match usage_tree.get(usage_key)? {
+                        Some(data) => read_json(&data)?,
+                        None => Usage::default(),
+                    };
+                    let usage = usage.with_blob(metadata.size.unwrap_or_default());
+                    if !quota.allows(usage) {
+                        return abort(TransactionAbort::User(Some(QuotaExceeded(scope))));
+                    }
+                    usage_tree.insert(usage_key.as_slice(), write_json(&usage)?)?;
+                    new_usage[i] = usage;
+                }
+
+                meta_tree.insert(key, write_json(metadata)?)?;
+                counter_tree.remove(key)?;
+                update_index(index_tree, &BTreeSet::new(), &index_keys(key, metadata))?;
+                Ok((new_usage[0], new_usage[1]))
+            },
+        );
+
+        match flatten_transaction(res)? {
+            Ok(usage) => Ok(Ok(usage)),
+            Err(Some(exceeded)) => Ok(Err(exceeded)),
+            Err(None) => Err(anyhow::anyhow!(
+                "Tried to create metadata for blob that already has it"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_key::generate_access_key;
+    use crate::quota::Quota;
+    use std::sync::Arc;
+    use std::thread;
+
+    const BLOB: &str = "/root/bucket/blob";
+
+    fn create_blob(metadata: &MetadataManager, meta: &BlobMetadata) -> BlobPath<PathExists> {
+        metadata
+            .create_metadata(&BlobPath::for_test(BLOB), meta, &BlobQuotas::default())
+            .unwrap()
+            .unwrap();
+        BlobPath::for_test(BLOB)
+    }
+
+    fn limited(max_downloads: u32) -> BlobMetadata {
+        BlobMetadata {
+            max_downloads: Some(max_downloads),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn concurrent_downloads_never_go_past_the_limit() {
+        let metadata = Arc::new(MetadataManager::temporary().unwrap());
+        create_blob(&metadata, &limited(5));
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let metadata = Arc::clone(&metadata);
+                thread::spawn(move || {
+                    let path = BlobPath::for_test(BLOB);
+                    (0..4)
+                        .filter(|_| {
+                            matches!(
+                                metadata.record_download(&path).unwrap(),
+                                DownloadOutcome::Allowed(_)
+                            )
+                        })
+                        .count()
+                })
+            })
+            .collect();
+        let allowed: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(allowed, 5);
+        let meta = metadata
+            .get_metadata(&BlobPath::for_test(BLOB), false)
+            .unwrap();
+        assert_eq!(meta.download_count, 5);
+        assert!(meta.deletion_date.is_some());
+    }
+
+    #[test]
+    fn last_allowed_download_deletes_the_blob() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &limited(1));
+
+        assert!(matches!(
+            metadata.record_download(&path).unwrap(),
+            DownloadOutcome::Allowed(m) if m.deletion_date.is_some()
+        ));
+        assert!(matches!(
+            metadata.record_download(&path).unwrap(),
+            DownloadOutcome::LimitReached
+        ));
+    }
+
+    #[test]
+    fn soft_deleted_blob_isnt_downloaded() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(
+            &metadata,
+            &BlobMetadata {
+                deletion_date: Some(Utc::now()),
+                ..Default::default()
+            },
+        );
+
+        assert!(matches!(
+            metadata.record_download(&path).unwrap(),
+            DownloadOutcome::Deleted
+        ));
+        assert_eq!(
+            metadata.get_metadata(&path, false).unwrap().download_count,
+            0
+        );
+    }
+
+    #[test]
+    fn lowering_the_limit_to_the_download_count_uses_up_the_blob() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &limited(5));
+        for _ in 0..2 {
+            metadata.record_download(&path).unwrap();
+        }
+
+        metadata
+            .update_metadata(&path, |meta| {
+                meta.max_downloads = Some(2);
+                Ok::<_, ()>(())
+            })
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            metadata.record_download(&path).unwrap(),
+            DownloadOutcome::LimitReached
+        ));
+        let meta = metadata.get_metadata(&path, false).unwrap();
+        assert_eq!(meta.download_count, 2);
+        assert!(meta.deletion_date.is_some());
+    }
+
+    #[test]
+    fn migration_hashes_plaintext_access_keys() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let strong = generate_access_key();
+        for (path, key) in [
+            ("/root/bucket/weak", "hunter2"),
+            ("/root/bucket/strong", &strong),
+        ] {
+            let mut legacy = serde_json::to_value(BlobMetadata::default()).unwrap();
+            legacy["access_key"] = key.into();
+            legacy.as_object_mut().unwrap().remove("access_key_hash");
+            metadata
+                .sled
+                .insert(path, serde_json::to_vec(&legacy).unwrap())
+                .unwrap();
+        }
+        metadata.schema.remove(SCHEMA_VERSION_KEY).unwrap();
+
+        metadata.migrate().unwrap();
+
+        for (path, key) in [
+            ("/root/bucket/weak", "hunter2"),
+            ("/root/bucket/strong", &strong),
+        ] {
+            let data = metadata.sled.get(path).unwrap().unwrap();
+            assert!(!String::from_utf8_lossy(&data).contains(key));
+            let meta: BlobMetadata = serde_json::from_slice(&data).unwrap();
+            assert!(meta.legacy_access_key.is_none());
+            assert!(meta.check_access_key(key));
+            assert!(!meta.check_access_key("hunter3"));
+        }
+    }
+
+    fn sized(size: u64) -> BlobMetadata {
+        BlobMetadata {
+            size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn blobs_past_the_bucket_quota_are_refused() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let quotas = BlobQuotas {
+            bucket: Quota {
+                max_objects: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let create = |name: &str| {
+            metadata
+                .create_metadata(&BlobPath::for_test(name), &sized(10), &quotas)
+                .unwrap()
+        };
+
+        assert!(create("/root/bucket/a").is_ok());
+        let (bucket, namespace) = create("/root/bucket/b").unwrap();
+        assert_eq!(
+            bucket,
+            Usage {
+                bytes: 20,
+                objects: 2
+            }
+        );
+        assert_eq!(namespace, bucket);
+
+        assert!(matches!(
+            create("/root/bucket/c"),
+            Err(QuotaExceeded(QuotaScope::Bucket))
+        ));
+        assert!(
+            metadata
+                .get_metadata(&BlobPath::for_test("/root/bucket/c"), false)
+                .is_err()
+        );
+        assert_eq!(
+            metadata.get_usage(Path::new("/root/bucket")).unwrap(),
+            Usage {
+                bytes: 20,
+                objects: 2
+            }
+        );
+        // Other buckets have their own quota
+        assert!(create("/root/other/a").is_ok());
+    }
+
+    #[test]
+    fn blobs_past_the_namespace_quota_are_refused() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let quotas = BlobQuotas {
+            namespace: Quota {
+                max_bytes: Some(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        for (name, size) in [("/root/a/blob", 60), ("/root/b/blob", 40)] {
+            assert!(
+                metadata
+                    .create_metadata(&BlobPath::for_test(name), &sized(size), &quotas)
+                    .unwrap()
+                    .is_ok()
+            );
+        }
+        assert!(matches!(
+            metadata
+                .create_metadata(&BlobPath::for_test("/root/c/blob"), &sized(1), &quotas)
+                .unwrap(),
+            Err(QuotaExceeded(QuotaScope::Tenant))
+        ));
+        // The refused blob isn't counted in its bucket either
+        assert_eq!(
+            metadata.get_usage(Path::new("/root/c")).unwrap(),
+            Usage::default()
+        );
+    }
+
+    #[test]
+    fn purging_a_blob_frees_its_usage() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &sized(10));
+        assert!(!metadata.remove_deleted_metadata(&path).unwrap());
+
+        metadata
+            .update_metadata(&path, |meta| {
+                meta.deletion_date = Some(Utc::now());
+                Ok::<_, ()>(())
+            })
+            .unwrap()
+            .unwrap();
+        // Soft-deleted blobs still count until they're purged
+        assert_eq!(
+            metadata.get_usage(Path::new("/root/bucket")).unwrap(),
+            Usage {
+                bytes: 10,
+                objects: 1
+            }
+        );
+
+        assert!(metadata.remove_deleted_metadata(&path).unwrap());
+        for usage_key in ["/root/bucket", "/root"] {
+            assert_eq!(
+                metadata.get_usage(Path::new(usage_key)).unwrap(),
+                Usage::default()
+            );
+        }
+    }
+
+    fn share(blob: &BlobPath<PathExists>, max_downloads: Option<u32>) -> ShareLink {
+        ShareLink {
+            id: crate::share::generate_share_id(),
+            blob_key: blob.as_os_str().as_bytes().to_vec(),
+            tenant: None,
+            bucket_name: "bucket".to_string(),
+            file_name: "blob".to_string(),
+            created_at: Utc::now(),
+            expires_at: Some(Utc::now() + chrono::Duration::hours(1)),
+            max_downloads,
+            download_count: 0,
+            password_hash: None,
+        }
+    }
+
+    #[test]
+    fn share_downloads_are_counted_with_the_blob() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &BlobMetadata::default());
+        let share = share(&path, Some(1));
+        metadata.create_share(&share).unwrap();
+
+        assert!(matches!(
+            metadata.record_share_download(&share.id, &path).unwrap(),
+            ShareOutcome::Blob(DownloadOutcome::Allowed(_))
+        ));
+        assert!(matches!(
+            metadata.record_share_download(&share.id, &path).unwrap(),
+            ShareOutcome::LimitReached
+        ));
+        assert_eq!(
+            metadata
+                .get_share(&share.id)
+                .unwrap()
+                .unwrap()
+                .download_count,
+            1
+        );
+        assert_eq!(
+            metadata.get_metadata(&path, false).unwrap().download_count,
+            1
+        );
+    }
+
+    #[test]
+    fn unavailable_blob_doesnt_use_up_the_share() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &limited(1));
+        metadata.record_download(&path).unwrap();
+        let share = share(&path, Some(1));
+        metadata.create_share(&share).unwrap();
+
+        assert!(matches!(
+            metadata.record_share_download(&share.id, &path).unwrap(),
+            ShareOutcome::Blob(DownloadOutcome::LimitReached)
+        ));
+        assert_eq!(
+            metadata
+                .get_share(&share.id)
+                .unwrap()
+                .unwrap()
+                .download_count,
+            0
+        );
+    }
+
+    #[test]
+    fn missing_share_isnt_downloaded() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let path = create_blob(&metadata, &BlobMetadata::default());
+
+        assert!(matches!(
+            metadata.record_share_download("nope", &path).unwrap(),
+            ShareOutcome::Missing
+        ));
+        assert_eq!(
+            metadata.get_metadata(&path, false).unwrap().download_count,
+            0
+        );
+    }
+
+    #[test]
+    fn removing_user_sessions_can_keep_one() {
+        let metadata = MetadataManager::temporary().unwrap();
+        let session = |username: &str| Session {
+            username: username.to_string(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        };
+        for (token, username) in [("s_1", "alice"), ("s_2", "alice"), ("s_3", "bob")] {
+            metadata.create_session(token, &session(username)).unwrap();
+        }
+
+        metadata.remove_user_sessions("alice", Some("s_1")).unwrap();
+        assert!(metadata.get_session("s_1").unwrap().is_some());
+        assert!(metadata.get_session("s_2").unwrap().is_none());
+        assert!(metadata.get_session("s_3").unwrap().is_some());
+
+        metadata.remove_user_sessions("alice", None).unwrap();
+        assert!(metadata.get_session("s_1").unwrap().is_none());
+        assert!(metadata.get_session("s_3").unwrap().is_some());
     }
 }
@@ -1,7 +1,10 @@
 use crate::settings::AppSettings;
+use crate::share::SHARE_PATH_PREFIX;
+use crate::tenant::TENANT_PATH_PREFIX;
 use actix_web::web::Data;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::os::unix::ffi::OsStringExt;
 use std::path::{Component, Path, PathBuf};
 
 pub struct PathExists;
@@ -17,6 +20,14 @@ impl<T> Deref for BlobPath<T> {
     }
 }
 
+/// A blob path that isn't checked against the filesystem, for tests that only use it as a metadata key
+#[cfg(test)]
+impl<T> BlobPath<T> {
+    pub fn for_test(path: &str) -> Self {
+        BlobPath(PathBuf::from(path), PhantomData)
+    }
+}
+
 pub struct BucketPath<Marker>(PathBuf, PhantomData<Marker>);
 
 impl<T> Deref for BucketPath<T> {
@@ -27,17 +38,73 @@ impl<T> Deref for BucketPath<T> {
     }
 }
 
+/// Directory under the storage root that holds the namespaces of every tenant
+pub const TENANTS_DIR: &str = "tenants";
+
+#[derive(Clone)]
 pub struct PathManager {
     settings: Data<AppSettings>,
+    /// Namespace of the tenant whose buckets this manages, `None` for the default namespace at the storage root
+    tenant: Option<String>,
 }
 
 impl PathManager {
     pub fn new(settings: Data<AppSettings>) -> Self {
-        Self { settings }
+        Self {
+            settings,
+            tenant: None,
+        }
     }
 
-    fn get_root(&self) -> PathBuf {
-        PathBuf::from(&self.settings.storage_root)
+    /// A path manager for the buckets of a single tenant, which can't reach any other namespace
+    pub fn for_tenant(&self, tenant: &str) -> Self {
+        Self {
+            settings: Data::clone(&self.settings),
+            tenant: Some(tenant.to_string()),
+        }
+    }
+
+    /// The tenant whose namespace this manages, `None` for the default namespace
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// The directory holding the buckets of this namespace
+    pub fn get_root(&self) -> PathBuf {
+        let root = PathBuf::from(&self.settings.storage_root);
+        match &self.tenant {
+            Some(tenant) => root.join(TENANTS_DIR).join(tenant),
+            None => root,
+        }
+    }
+
+    /// The root of this namespace, as the prefix every metadata key in it starts with
+    pub fn root_key_prefix(&self) -> Vec<u8> {
+        let mut prefix = self.get_root().into_os_string().into_vec();
+        prefix.push(b'/');
+        prefix
+    }
+
+    /// Create the root directory of this namespace
+    pub fn create_root(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.get_root())
+    }
+
+    /// Names that can't be buckets, as their URLs would be taken by other routes, or in the default namespace
+    /// because they're where the tenants are stored
+    fn is_reserved(&self, bucket_name: &Path) -> bool {
+        // Leading `/` and `.` are ignored when joining, so look at the first normal component
+        let Some(Component::Normal(name)) = bucket_name
+            .components()
+            .find(|c| matches!(c, Component::Normal(_)))
+        else {
+            return false;
+        };
+
+        // Share links and tenant prefixes would take the bucket's URLs in every namespace
+        name == SHARE_PATH_PREFIX.trim_matches('/')
+            || name == TENANT_PATH_PREFIX.trim_matches('/')
+            || (self.tenant.is_none() && name == TENANTS_DIR)
     }
 
     /// Safely join a path to the root
@@ -83,6 +150,10 @@ impl PathManager {
     /// - Point to a new, non existent, bucket
     /// - Hold all the assumptions of [Self::safe_join]
     pub fn create_bucket(&self, bucket_name: &Path) -> Option<BucketPath<PathDoesntExist>> {
+        if self.is_reserved(bucket_name) {
+            return None;
+        }
+
         let path = self.safe_join(&self.get_root(), bucket_name)?;
 
         // End result must *not* exist
@@ -100,6 +171,10 @@ impl PathManager {
     pub fn get_bucket(&self, bucket_name: &Path) -> Option<BucketPath<PathExists>> {
         let _span = tracing::info_span!("bucket_get").entered();
 
+        if self.is_reserved(bucket_name) {
+            return None;
+        }
+
         let path = self.safe_join(&self.get_root(), bucket_name)?;
 
         // End result must exist
@@ -147,4 +222,46 @@ impl PathManager {
 
         Some(BlobPath(path, Default::default()))
     }
+
+    /// Find the bucket and file name of a blob from its path
+    pub fn locate_blob(&self, blob_path: &Path) -> Option<(String, String)> {
+        let relative = blob_path.strip_prefix(self.get_root()).ok()?;
+        let mut components = relative.components();
+        let bucket = components.next()?.as_os_str().to_str()?.to_string();
+        if self.is_reserved(Path::new(&bucket)) {
+            return None;
+        }
+        let file = components.as_path().to_str()?.to_string();
+        Some((bucket, file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths() -> PathManager {
+        PathManager::new(Data::new(AppSettings::for_test(None)))
+    }
+
+    #[test]
+    fn route_names_are_reserved_in_every_namespace() {
+        for paths in [paths(), paths().for_tenant("acme")] {
+            assert!(paths.is_reserved(Path::new("s")));
+            assert!(paths.is_reserved(Path::new("/./s")));
+            assert!(paths.is_reserved(Path::new("t")));
+            assert!(!paths.is_reserved(Path::new("photos")));
+            assert!(!paths.is_reserved(Path::new("shares")));
+        }
+    }
+
+    #[test]
+    fn tenants_dir_is_only_reserved_in_the_default_namespace() {
+        assert!(paths().is_reserved(Path::new(TENANTS_DIR)));
+        assert!(
+            !paths()
+                .for_tenant("acme")
+                .is_reserved(Path::new(TENANTS_DIR))
+        );
+    }
 }
@@ -0,0 +1,308 @@
+//! Presigned URLs
+//! A presigned URL lets whoever holds it download or upload a single blob until it expires, without any other
+//! credentials. The URL's query holds the expiry, any constraints on the upload, and a signature over all of them made
+//! with the server's token secret
+
+use crate::settings::AppSettings;
+use crate::tenant::TENANT_PATH_PREFIX;
+use crate::token::{HmacSha256, mac};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::Mac;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use serde::{Deserialize, Serialize};
+
+/// Everything but the unreserved characters of RFC 3986
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// What a presigned URL can be used for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PresignMethod {
+    Get,
+    Put,
+}
+
+impl PresignMethod {
+    fn as_str(self) -> &'static str {
+        match self {
+            PresignMethod::Get => "GET",
+            PresignMethod::Put => "PUT",
+        }
+    }
+}
+
+/// Query parameters of a presigned URL, all empty for normal requests
+#[derive(Deserialize, Debug, Default)]
+pub struct PresignQuery {
+    /// Unix timestamp the URL stops working at
+    pub expires: Option<i64>,
+    pub signature: Option<String>,
+    /// Uploads must have exactly this content type
+    pub content_type: Option<String>,
+    /// Uploads must be no bigger than this many bytes
+    pub max_size: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn signature(
+    secret: &str,
+    method: PresignMethod,
+    tenant: Option<&str>,
+    bucket: &str,
+    file: &str,
+    expires: i64,
+    content_type: Option<&str>,
+    max_size: Option<u64>,
+) -> HmacSha256 {
+    let mut mac = mac(secret);
+    let max_size = max_size.map(|s| s.to_string()).unwrap_or_default();
+    // Length prefix each part so they can't be shifted between fields
+    for part in [
+        "presign",
+        method.as_str(),
+        bucket,
+        file,
+        &expires.to_string(),
+        content_type.unwrap_or_default(),
+        &max_size,
+    ] {
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part.as_bytes());
+    }
+    // Only added for tenants, so URLs made before tenants existed stay valid
+    if let Some(tenant) = tenant {
+        mac.update(&(tenant.len() as u64).to_be_bytes());
+        mac.update(tenant.as_bytes());
+    }
+    mac
+}
+
+/// Make the path and query of a presigned URL for `file` in `bucket` of `tenant`
+#[allow(clippy::too_many_arguments)]
+pub fn presign_path(
+    secret: &str,
+    method: PresignMethod,
+    tenant: Option<&str>,
+    bucket: &str,
+    file: &str,
+    expires_at: DateTime<Utc>,
+    content_type: Option<&str>,
+    max_size: Option<u64>,
+) -> String {
+    let expires = expires_at.timestamp();
+    let sig = signature(
+        secret,
+        method,
+        tenant,
+        bucket,
+        file,
+        expires,
+        content_type,
+        max_size,
+    );
+
+    let encode = |s: &str| utf8_percent_encode(s, URL_COMPONENT).to_string();
+
+    let mut path = match method {
+        PresignMethod::Get => format!("/{}/{}", encode(bucket), encode(file)),
+        PresignMethod::Put => format!("/api/bucket/{}/{}/upload", encode(bucket), encode(file)),
+    };
+    if let Some(tenant) = tenant {
+        path = format!("{}{}{}", TENANT_PATH_PREFIX, tenant, path);
+    }
+
+    let mut query = format!(
+        "expires={}&signature={}",
+        expires,
+        URL_SAFE_NO_PAD.encode(sig.finalize().into_bytes())
+    );
+    if let Some(content_type) = content_type {
+        query.push_str(&format!("&content_type={}", encode(content_type)));
+    }
+    if let Some(max_size) = max_size {
+        query.push_str(&format!("&max_size={}", max_size));
+    }
+
+    format!("{}?{}", path, query)
+}
+
+impl PresignQuery {
+    /// Is this a valid, unexpired presigned URL for `method` on `file` in `bucket` of `tenant`
+    pub fn verify(
+        &self,
+        settings: &AppSettings,
+        method: PresignMethod,
+        tenant: Option<&str>,
+        bucket: &str,
+        file: &str,
+    ) -> bool {
+        let (Some(secret), Some(expires), Some(given)) =
+            (&settings.token_secret, self.expires, &self.signature)
+        else {
+            return false;
+        };
+
+        if expires <= Utc::now().timestamp() {
+            tracing::warn!("Attempt to use expired presigned URL");
+            return false;
+        }
+
+        let Ok(given) = URL_SAFE_NO_PAD.decode(given) else {
+            return false;
+        };
+
+        let valid = signature(
+            secret,
+            method,
+            tenant,
+            bucket,
+            file,
+            expires,
+            self.content_type.as_deref(),
+            self.max_size,
+        )
+        .verify_slice(&given)
+        .is_ok();
+
+        if !valid {
+            tracing::warn!("Invalid presigned URL signature");
+        }
+        valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Query;
+    use chrono::Duration;
+
+    const SECRET: &str = "secret";
+
+    fn presign(method: PresignMethod, content_type: Option<&str>, max_size: Option<u64>) -> String {
+        presign_path(
+            SECRET,
+            method,
+            None,
+            "photos",
+            "cat picture.png",
+            Utc::now() + Duration::hours(1),
+            content_type,
+            max_size,
+        )
+    }
+
+    fn query(url: &str) -> PresignQuery {
+        let (_, query) = url.split_once('?').unwrap();
+        Query::<PresignQuery>::from_query(query)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn presigned_url_verifies_for_its_blob_and_method() {
+        let settings = AppSettings::for_test(Some(SECRET));
+        let url = presign(PresignMethod::Get, None, None);
+        assert!(url.starts_with("/photos/cat%20picture.png?"));
+        let query = query(&url);
+
+        assert!(query.verify(
+            &settings,
+            PresignMethod::Get,
+            None,
+            "photos",
+            "cat picture.png"
+        ));
+        assert!(!query.verify(
+            &settings,
+            PresignMethod::Put,
+            None,
+            "photos",
+            "cat picture.png"
+        ));
+        assert!(!query.verify(&settings, PresignMethod::Get, None, "photos", "dog.png"));
+        assert!(!query.verify(
+            &settings,
+            PresignMethod::Get,
+            None,
+            "videos",
+            "cat picture.png"
+        ));
+        assert!(!query.verify(
+            &settings,
+            PresignMethod::Get,
+            Some("acme"),
+            "photos",
+            "cat picture.png"
+        ));
+        assert!(!query.verify(
+            &AppSettings::for_test(Some("other secret")),
+            PresignMethod::Get,
+            None,
+            "photos",
+            "cat picture.png"
+        ));
+        assert!(!query.verify(
+            &AppSettings::for_test(None),
+            PresignMethod::Get,
+            None,
+            "photos",
+            "cat picture.png"
+        ));
+    }
+
+    #[test]
+    fn tampered_upload_constraints_are_rejected() {
+        let settings = AppSettings::for_test(Some(SECRET));
+        let verify = |query: &PresignQuery| {
+            query.verify(
+                &settings,
+                PresignMethod::Put,
+                None,
+                "photos",
+                "cat picture.png",
+            )
+        };
+        let url = presign(PresignMethod::Put, Some("image/png"), Some(1024));
+        assert!(verify(&query(&url)));
+
+        let mut bigger = query(&url);
+        bigger.max_size = Some(1024 * 1024);
+        assert!(!verify(&bigger));
+
+        let mut unlimited = query(&url);
+        unlimited.max_size = None;
+        assert!(!verify(&unlimited));
+
+        let mut html = query(&url);
+        html.content_type = Some("text/html".to_string());
+        assert!(!verify(&html));
+
+        let mut later = query(&url);
+        later.expires = later.expires.map(|e| e + 3600);
+        assert!(!verify(&later));
+    }
+
+    #[test]
+    fn expired_url_is_rejected() {
+        let settings = AppSettings::for_test(Some(SECRET));
+        let url = presign_path(
+            SECRET,
+            PresignMethod::Get,
+            None,
+            "photos",
+            "cat.png",
+            Utc::now() - Duration::seconds(1),
+            None,
+            None,
+        );
+
+        assert!(!query(&url).verify(&settings, PresignMethod::Get, None, "photos", "cat.png"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Storage quotas
+//! The bytes and number of blobs stored in every bucket, and in every namespace, are counted as blobs are created and
+//! purged. Soft-deleted blobs still take up space, so they count until they are purged.
+//! Buckets and tenants can have hard limits, which uploads can't go past, and soft limits, which are only warned about
+
+use crate::metadata::MetadataManager;
+use crate::path::{BucketPath, PathExists, PathManager};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Space used by a bucket or namespace
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub objects: u64,
+}
+
+impl Usage {
+    /// Usage after adding a blob of `size` bytes
+    pub fn with_blob(self, size: u64) -> Self {
+        Self {
+            bytes: self.bytes.saturating_add(size),
+            objects: self.objects.saturating_add(1),
+        }
+    }
+
+    /// Usage after purging a blob of `size` bytes
+    pub fn without_blob(self, size: u64) -> Self {
+        Self {
+            bytes: self.bytes.saturating_sub(size),
+            objects: self.objects.saturating_sub(1),
+        }
+    }
+}
+
+/// Limits on the [Usage] of a bucket or namespace, `None` is unlimited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quota {
+    /// Uploads that would store more than this are rejected
+    pub max_bytes: Option<u64>,
+    /// Uploads that would store more blobs than this are rejected
+    pub max_objects: Option<u64>,
+    /// Uploads that store more than this are warned about
+    pub warn_bytes: Option<u64>,
+    /// Uploads that store more blobs than this are warned about
+    pub warn_objects: Option<u64>,
+}
+
+impl Quota {
+    /// Is `usage` within the hard limits
+    pub fn allows(&self, usage: Usage) -> bool {
+        self.max_bytes.is_none_or(|max| usage.bytes <= max)
+            && self.max_objects.is_none_or(|max| usage.objects <= max)
+    }
+
+    /// Is `usage` past the soft limits
+    pub fn warns(&self, usage: Usage) -> bool {
+        self.warn_bytes.is_some_and(|warn| usage.bytes > warn)
+            || self.warn_objects.is_some_and(|warn| usage.objects > warn)
+    }
+
+    /// How many more bytes can be stored, `None` if there is no byte limit
+    pub fn remaining_bytes(&self, usage: Usage) -> Option<u64> {
+        self.max_bytes.map(|max| max.saturating_sub(usage.bytes))
+    }
+}
+
+/// What a quota applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaScope {
+    Bucket,
+    Tenant,
+}
+
+impl fmt::Display for QuotaScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaScope::Bucket => write!(f, "Bucket"),
+            QuotaScope::Tenant => write!(f, "Tenant"),
+        }
+    }
+}
+
+/// The quotas that apply to a new blob, those of its bucket and of its namespace
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlobQuotas {
+    pub bucket: Quota,
+    pub namespace: Quota,
+}
+
+/// An upload would go past a hard quota
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaExceeded(pub QuotaScope);
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} storage quota exceeded", self.0)
+    }
+}
+
+/// How much a new blob in a bucket is allowed to store
+pub struct UploadAllowance {
+    pub quotas: BlobQuotas,
+    /// Most bytes the blob can hold, `None` if there is no byte limit
+    pub max_bytes: Option<u64>,
+}
+
+/// Check that a new blob can be added to `bucket` at all, and how big it can be
+/// Concurrent uploads can use up the same space, so the quotas are checked again when the blob is stored
+pub fn upload_allowance(
+    metadata: &MetadataManager,
+    paths: &PathManager,
+    bucket: &BucketPath<PathExists>,
+) -> anyhow::Result<Result<UploadAllowance, QuotaExceeded>> {
+    let quotas = BlobQuotas {
+        bucket: metadata
+            .get_bucket_config(bucket)?
+            .map(|c| c.quota)
+            .unwrap_or_default(),
+        namespace: match paths.tenant() {
+            Some(tenant) => metadata
+                .get_tenant(tenant)?
+                .map(|t| t.quota)
+                .unwrap_or_default(),
+            None => Quota::default(),
+        },
+    };
+
+    let mut max_bytes: Option<u64> = None;
+    for (quota, usage, scope) in [
+        (
+            quotas.bucket,
+            metadata.get_usage(bucket)?,
+            QuotaScope::Bucket,
+        ),
+        (
+            quotas.namespace,
+            metadata.get_usage(&paths.get_root())?,
+            QuotaScope::Tenant,
+        ),
+    ] {
+        if !quota.allows(usage.with_blob(0)) {
+            return Ok(Err(QuotaExceeded(scope)));
+        }
+
+        if let Some(remaining) = quota.remaining_bytes(usage) {
+            max_bytes = Some(max_bytes.map_or(remaining, |max| max.min(remaining)));
+        }
+    }
+
+    Ok(Ok(UploadAllowance { quotas, max_bytes }))
+}
@@ -0,0 +1,604 @@
+//! Rate limiting
+//! Requests are split into route groups, and each group can be limited per client IP and per credential with a token
+//! bucket: a client can make up to the limit's number of requests at once, and gets them back evenly over its period.
+//! Separately, an IP that fails authentication too many times is locked out of everything for a while. A failure is a
+//! 401, or a request whose credentials the [crate::auth::Authorizer] refused, whatever the handler answered with.
+//! Limits are kept in memory, so they are per instance and reset on restart
+//!
+//! Everything is configured from the environment, and unset limits don't apply:
+//! - `RATE_LIMIT_IP_<GROUP>` and `RATE_LIMIT_KEY_<GROUP>`, where the group is `DOWNLOAD`, `UPLOAD` or `API`, as
+//!   `<requests>/<period>` with a period of `s`, `m` or `h`, e.g. `600/m`
+//! - `AUTH_LOCKOUT_FAILURES`, failed authentications after which an IP is locked out
+//! - `AUTH_LOCKOUT_SECS`, how long failures are remembered and lockouts last, 15 minutes by default
+//! - `RATE_LIMIT_TRUST_PROXY`, take the client IP from the last hop of `Forwarded` or `X-Forwarded-For`, which is the
+//!   one added by the proxy in front of this server. Only set this behind exactly one proxy that appends to those
+//!   headers, otherwise clients can pick their own IP and dodge their limits or lock out others
+
+use crate::auth::AuthResult;
+use crate::tenant::TENANT_PATH_PREFIX;
+use crate::token::bearer_token;
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::{Method, StatusCode, header};
+use actix_web::web::Data;
+use actix_web::{Error as AWError, HttpMessage, HttpResponse};
+use anyhow::Context;
+use futures::future::LocalBoxFuture;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Clients tracked before idle ones are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How long lockouts last if `AUTH_LOCKOUT_SECS` isn't set
+const DEFAULT_LOCKOUT_SECS: u64 = 15 * 60;
+
+/// Kinds of requests that are limited separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    /// Reading blobs and share links
+    Download,
+    /// Uploading blobs
+    Upload,
+    /// Everything else under `/api/`
+    Api,
+}
+
+impl RouteGroup {
+    const ALL: [RouteGroup; 3] = [RouteGroup::Download, RouteGroup::Upload, RouteGroup::Api];
+
+    fn env_name(self) -> &'static str {
+        match self {
+            RouteGroup::Download => "DOWNLOAD",
+            RouteGroup::Upload => "UPLOAD",
+            RouteGroup::Api => "API",
+        }
+    }
+
+    fn for_request(method: &Method, path: &str) -> Self {
+        // Requests routed by path prefix are limited the same as any other tenant's
+        let path = path
+            .strip_prefix(TENANT_PATH_PREFIX)
+            .and_then(|p| p.split_once('/'))
+            .map_or(path, |(_, rest)| rest.trim_start_matches('/'));
+        let path = path.trim_start_matches('/');
+
+        if !path.starts_with("api/") {
+            RouteGroup::Download
+        } else if *method == Method::PUT && path.ends_with("/upload") {
+            RouteGroup::Upload
+        } else {
+            RouteGroup::Api
+        }
+    }
+}
+
+/// A number of requests allowed per period
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    /// Parse `<requests>/<period>`, e.g. `10/s`, `600/m` or `1000/h`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (requests, period) = s.trim().split_once('/')?;
+        let requests = requests.trim().parse::<u32>().ok().filter(|r| *r > 0)?;
+        let period = match period.trim() {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            _ => return None,
+        };
+        Some(Self { requests, period })
+    }
+
+    fn from_env(name: &str) -> anyhow::Result<Option<Self>> {
+        match env::var(name) {
+            Ok(v) if !v.is_empty() => Self::parse(&v)
+                .map(Some)
+                .ok_or_else(|| anyhow::anyhow!("Invalid {} {}, expected e.g. 600/m", name, v)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Tokens regained per second
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.requests) / self.period.as_secs_f64()
+    }
+}
+
+/// Limits of one [RouteGroup]
+#[derive(Debug, Clone, Default)]
+pub struct GroupLimits {
+    pub per_ip: Option<RateLimit>,
+    pub per_key: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitSettings {
+    pub groups: HashMap<RouteGroup, GroupLimits>,
+    /// Failed authentications after which an IP is locked out, `None` never locks anyone out
+    pub lockout_failures: Option<u32>,
+    pub lockout_duration: Duration,
+    pub trust_proxy: bool,
+}
+
+impl RateLimitSettings {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut groups = HashMap::new();
+        for group in RouteGroup::ALL {
+            groups.insert(
+                group,
+                GroupLimits {
+                    per_ip: RateLimit::from_env(&format!("RATE_LIMIT_IP_{}", group.env_name()))?,
+                    per_key: RateLimit::from_env(&format!("RATE_LIMIT_KEY_{}", group.env_name()))?,
+                },
+            );
+        }
+
+        let lockout_failures = match env::var("AUTH_LOCKOUT_FAILURES") {
+            Ok(v) if !v.is_empty() => Some(
+                v.parse::<u32>()
+                    .ok()
+                    .filter(|f| *f > 0)
+                    .context("Invalid AUTH_LOCKOUT_FAILURES")?,
+            ),
+            _ => None,
+        };
+
+        let lockout_secs = match env::var("AUTH_LOCKOUT_SECS") {
+            Ok(v) if !v.is_empty() => v.parse::<u64>().context("Invalid AUTH_LOCKOUT_SECS")?,
+            _ => DEFAULT_LOCKOUT_SECS,
+        };
+
+        Ok(Self {
+            groups,
+            lockout_failures,
+            lockout_duration: Duration::from_secs(lockout_secs),
+            trust_proxy: env::var("RATE_LIMIT_TRUST_PROXY").is_ok_and(|v| v == "true" || v == "1"),
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.lockout_failures.is_some()
+            || self
+                .groups
+                .values()
+                .any(|g| g.per_ip.is_some() || g.per_key.is_some())
+    }
+}
+
+/// Who a token bucket belongs to
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    /// Hash of the credential, so keys aren't kept in memory
+    Key([u8; 32]),
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.requests),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_rate()).min(f64::from(limit.requests));
+        self.updated = now;
+    }
+
+    /// Take a token, or how long until one is available
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.refill_rate(),
+            ))
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * limit.refill_rate() >= f64::from(limit.requests)
+    }
+}
+
+#[derive(Debug)]
+struct AuthFailures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Shared state of the rate limits, for every worker
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<HashMap<(RouteGroup, Client), TokenBucket>>,
+    failures: Mutex<HashMap<IpAddr, AuthFailures>>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        Self {
+            settings,
+            buckets: Mutex::new(HashMap::new()),
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of `client`, or how long until it can make another request
+    fn take(
+        &self,
+        group: RouteGroup,
+        client: Client,
+        limit: &RateLimit,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().expect("rate limit lock");
+
+        // Clients whose buckets have refilled are the same as ones never seen
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|(group, client), bucket| {
+                self.limit(*group, client)
+                    .is_some_and(|limit| !bucket.is_full(limit, now))
+            });
+        }
+
+        buckets
+            .entry((group, client))
+            .or_insert_with(|| TokenBucket::full(limit, now))
+            .take(limit, now)
+    }
+
+    fn limit(&self, group: RouteGroup, client: &Client) -> Option<&RateLimit> {
+        let limits = self.settings.groups.get(&group)?;
+        match client {
+            Client::Ip(_) => limits.per_ip.as_ref(),
+            Client::Key(_) => limits.per_key.as_ref(),
+        }
+    }
+
+    /// How much longer `ip` is locked out for, if it is
+    fn locked_out(&self, ip: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().expect("auth failure lock");
+        let until = failures.get(&ip)?.locked_until?;
+        (until > now).then(|| until - now)
+    }
+
+    /// Count a failed authentication from `ip`, locking it out once it has failed too often
+    fn record_failure(&self, ip: IpAddr, now: Instant) {
+        let Some(max_failures) = self.settings.lockout_failures else {
+            return;
+        };
+        let window = self.settings.lockout_duration;
+
+        let mut failures = self.failures.lock().expect("auth failure lock");
+        if failures.len() >= MAX_TRACKED_CLIENTS {
+            failures.retain(|_, f| {
+                now.saturating_duration_since(f.last) < window
+                    || f.locked_until.is_some_and(|until| until > now)
+            });
+        }
+
+        let entry = failures.entry(ip).or_insert(AuthFailures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+
+        // Failures are forgotten once they're old enough, and so are lockouts that have ended
+        if now.saturating_duration_since(entry.last) >= window
+            || entry.locked_until.is_some_and(|until| until <= now)
+        {
+            entry.count = 0;
+            entry.locked_until = None;
+        }
+
+        entry.count += 1;
+        entry.last = now;
+        if entry.count >= max_failures && entry.locked_until.is_none() {
+            tracing::warn!(
+                "Locking out {} after {} failed authentications",
+                ip,
+                entry.count
+            );
+            entry.locked_until = Some(now + window);
+        }
+    }
+
+    /// Check a request against the limits, giving how long to wait if it's over them
+    fn check(
+        &self,
+        req: &ServiceRequest,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        if let Some(ip) = ip
+            && let Some(remaining) = self.locked_out(ip, now)
+        {
+            tracing::warn!("Request from locked out {}", ip);
+            return Err(remaining);
+        }
+
+        let group = RouteGroup::for_request(req.method(), req.path());
+        let Some(limits) = self.settings.groups.get(&group) else {
+            return Ok(());
+        };
+
+        if let (Some(limit), Some(ip)) = (&limits.per_ip, ip) {
+            self.take(group, Client::Ip(ip), limit, now)
+                .inspect_err(|_| tracing::warn!("{} over {:?} rate limit", ip, group))?;
+        }
+
+        if let (Some(limit), Some(key)) = (&limits.per_key, request_credential(req)) {
+            self.take(group, key, limit, now)
+                .inspect_err(|_| tracing::warn!("Credential over {:?} rate limit", group))?;
+        }
+
+        Ok(())
+    }
+
+    fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        if self.settings.trust_proxy
+            && let Some(ip) = forwarded_ip(req)
+        {
+            return Some(ip);
+        }
+        req.peer_addr().map(|a| a.ip())
+    }
+}
+
+/// The client IP added by the proxy, the last hop of `Forwarded` or `X-Forwarded-For`
+/// Earlier hops come from the client, so can't be trusted
+fn forwarded_ip(req: &ServiceRequest) -> Option<IpAddr> {
+    let last_hop = |name: header::HeaderName| {
+        req.headers()
+            .get_all(name)
+            .last()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(str::trim)
+    };
+
+    let addr = match last_hop(header::FORWARDED) {
+        Some(hop) => hop
+            .split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+            .map(|(_, addr)| addr.trim_matches('"'))?,
+        None => last_hop(header::X_FORWARDED_FOR)?,
+    };
+
+    // Forwarded addresses can include a port, and IPv6 addresses are then in brackets
+    addr.parse()
+        .ok()
+        .or_else(|| addr.parse::<std::net::SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            addr.strip_prefix('[')
+                .and_then(|a| a.strip_suffix(']'))
+                .and_then(|a| a.parse().ok())
+        })
+}
+
+/// The credential a request carries, hashed
+fn request_credential(req: &ServiceRequest) -> Option<Client> {
+    let key = bearer_token(req.request())
+        .map(str::to_string)
+        .or_else(|| {
+            req.headers()
+                .get("X-Blob-Access-Key")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        })
+        .or_else(|| {
+            req.query_string()
+                .split('&')
+                .find_map(|p| p.strip_prefix("auth="))
+                .map(str::to_string)
+        })
+        .filter(|k| !k.is_empty())?;
+
+    Some(Client::Key(Sha256::digest(key.as_bytes()).into()))
+}
+
+/// Middleware applying the [RateLimiter] in the app data, if there is one
+pub fn limit_requests<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, AWError>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = AWError>,
+    S::Future: 'static,
+{
+    let Some(limiter) = req
+        .app_data::<Data<RateLimiter>>()
+        .filter(|l| l.settings.is_enabled())
+        .cloned()
+    else {
+        return Box::pin(srv.call(req));
+    };
+
+    let now = Instant::now();
+    let ip = limiter.client_ip(&req);
+    if let Err(retry_after) = limiter.check(&req, ip, now) {
+        let response = HttpResponse::TooManyRequests()
+            .insert_header((
+                header::RETRY_AFTER,
+                retry_after.as_secs_f64().ceil().max(1.0).to_string(),
+            ))
+            .finish();
+        return Box::pin(async move { Ok(req.into_response(response)) });
+    }
+
+    let fut = srv.call(req);
+    Box::pin(async move {
+        let res = fut.await?;
+        let refused = res.request().extensions().get::<AuthResult>() == Some(&AuthResult::Refused);
+        if let Some(ip) = ip
+            && (res.status() == StatusCode::UNAUTHORIZED || refused)
+        {
+            limiter.record_failure(ip, Instant::now());
+        }
+        Ok(res)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{TestRequest, call_service, init_service};
+    use actix_web::{App, HttpRequest, web};
+
+    const IP: &str = "192.0.2.1";
+
+    fn limiter(lockout_failures: Option<u32>) -> RateLimiter {
+        RateLimiter::new(RateLimitSettings {
+            groups: HashMap::new(),
+            lockout_failures,
+            lockout_duration: Duration::from_secs(60),
+            trust_proxy: false,
+        })
+    }
+
+    #[test]
+    fn parses_limits() {
+        assert_eq!(
+            RateLimit::parse("600/m"),
+            Some(RateLimit {
+                requests: 600,
+                period: Duration::from_secs(60)
+            })
+        );
+        assert_eq!(
+            RateLimit::parse(" 10 / s "),
+            Some(RateLimit {
+                requests: 10,
+                period: Duration::from_secs(1)
+            })
+        );
+        assert_eq!(
+            RateLimit::parse("1000/h").map(|l| l.period),
+            Some(Duration::from_secs(60 * 60))
+        );
+
+        for invalid in ["", "10", "0/s", "-1/s", "10/d", "ten/m", "10/"] {
+            assert_eq!(RateLimit::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn token_bucket_refills_over_its_period() {
+        let limit = RateLimit::parse("2/s").unwrap();
+        let now = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, now);
+
+        assert!(bucket.take(&limit, now).is_ok());
+        assert!(bucket.take(&limit, now).is_ok());
+        assert!(bucket.take(&limit, now).is_err());
+        assert!(
+            bucket
+                .take(&limit, now + Duration::from_millis(500))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn ip_is_locked_out_after_too_many_failures() {
+        let limiter = limiter(Some(3));
+        let ip = IP.parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..2 {
+            limiter.record_failure(ip, now);
+        }
+        assert_eq!(limiter.locked_out(ip, now), None);
+
+        limiter.record_failure(ip, now);
+        assert_eq!(limiter.locked_out(ip, now), Some(Duration::from_secs(60)));
+        assert_eq!(limiter.locked_out(ip, now + Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let limiter = limiter(Some(2));
+        let ip = IP.parse().unwrap();
+        let now = Instant::now();
+
+        limiter.record_failure(ip, now);
+        let later = now + Duration::from_secs(61);
+        limiter.record_failure(ip, later);
+        assert_eq!(limiter.locked_out(ip, later), None);
+    }
+
+    #[test]
+    fn no_lockout_without_a_failure_limit() {
+        let limiter = limiter(None);
+        let ip = IP.parse().unwrap();
+        let now = Instant::now();
+
+        for _ in 0..100 {
+            limiter.record_failure(ip, now);
+        }
+        assert_eq!(limiter.locked_out(ip, now), None);
+    }
+
+    async fn refused(req: HttpRequest) -> HttpResponse {
+        req.extensions_mut().insert(AuthResult::Refused);
+        HttpResponse::NotFound().finish()
+    }
+
+    async fn allowed(req: HttpRequest) -> HttpResponse {
+        req.extensions_mut().insert(AuthResult::Allowed);
+        HttpResponse::NotFound().finish()
+    }
+
+    #[actix_web::test]
+    async fn refused_credentials_count_towards_a_lockout_whatever_the_status() {
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(limiter(Some(3))))
+                .wrap_fn(limit_requests)
+                .route("/refused", web::get().to(refused))
+                .route("/allowed", web::get().to(allowed)),
+        )
+        .await;
+        let get = |path: &str| {
+            TestRequest::get()
+                .uri(path)
+                .peer_addr(format!("{}:1234", IP).parse().unwrap())
+                .to_request()
+        };
+
+        for _ in 0..5 {
+            let res = call_service(&app, get("/allowed")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        for _ in 0..3 {
+            let res = call_service(&app, get("/refused")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        let res = call_service(&app, get("/allowed")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+    }
+}
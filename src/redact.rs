@@ -0,0 +1,90 @@
+//! Helpers for keeping secrets out of logs
+
+use crate::share::SHARE_PATH_PREFIX;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+
+/// Headers that contain credentials
+const SECRET_HEADERS: &[&str] = &["x-blob-access-key", "authorization", "cookie"];
+
+/// Query parameters that contain credentials
+const SECRET_QUERY_PARAMS: &[&str] = &["auth", "signature"];
+
+const REDACTED: &str = "<redacted>";
+
+/// Format headers for logging, with the values of any that contain secrets hidden
+pub fn redact_headers(headers: &HeaderMap) -> String {
+    let headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            if SECRET_HEADERS.contains(&name.as_str()) {
+                format!("{}: {}", name, REDACTED)
+            } else {
+                format!("{}: {:?}", name, value)
+            }
+        })
+        .collect();
+    format!("[{}]", headers.join(", "))
+}
+
+/// Remove the values of any secret query parameters
+pub fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SECRET_QUERY_PARAMS.contains(&name) => {
+                format!("{}={}", name, REDACTED)
+            }
+            _ => param.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Remove the id of a share link from a request path, as it's as good as a key
+fn redact_path(path: &str) -> String {
+    match path.strip_prefix(SHARE_PATH_PREFIX) {
+        Some(id) if !id.is_empty() => format!("{}{}", SHARE_PATH_PREFIX, REDACTED),
+        _ => path.to_string(),
+    }
+}
+
+/// The request line for the access log, with share ids and secret query parameters removed
+pub fn redact_request_line(req: &ServiceRequest) -> String {
+    let path = redact_path(req.path());
+    let path = match req.query_string() {
+        "" => path,
+        query => format!("{}?{}", path, redact_query(query)),
+    };
+    format!("{} {} {:?}", req.method(), path, req.version())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn secret_query_params_are_redacted() {
+        assert_eq!(
+            redact_query("expires=10&signature=abc&download=1"),
+            "expires=10&signature=<redacted>&download=1"
+        );
+        assert_eq!(redact_query("auth=key"), "auth=<redacted>");
+        assert_eq!(redact_query("author=me"), "author=me");
+    }
+
+    #[test]
+    fn share_ids_are_redacted_from_the_request_line() {
+        let req = TestRequest::get()
+            .uri("/s/AbCdEf1234?download=1&auth=key")
+            .to_srv_request();
+        assert_eq!(
+            redact_request_line(&req),
+            "GET /s/<redacted>?download=1&auth=<redacted> HTTP/1.1"
+        );
+
+        let req = TestRequest::get().uri("/photos/s/cat.png").to_srv_request();
+        assert_eq!(redact_request_line(&req), "GET /photos/s/cat.png HTTP/1.1");
+    }
+}
@@ -0,0 +1,114 @@
+//! Response headers of downloads
+//! Blobs can be given headers such as `Cache-Control` on upload, which are sent with every download of them. Buckets
+//! can set defaults, which apply to any of these headers a blob doesn't set itself
+
+use actix_web::HttpResponseBuilder;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of upload headers that set a response header, e.g. `X-Blob-Response-Cache-Control`
+pub const RESPONSE_HEADER_PREFIX: &str = "x-blob-response-";
+
+/// Headers sent with downloads, `None` leaves it to the bucket's default
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResponseHeaders {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_language: Option<String>,
+    /// Lets already compressed content be served as such, it's never compressed again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    /// Only sent with blobs that are available for ever, blobs with a download limit or embargo are never cached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+}
+
+impl ResponseHeaders {
+    fn fields(&self) -> [(HeaderName, &Option<String>); 4] {
+        [
+            (header::CACHE_CONTROL, &self.cache_control),
+            (header::CONTENT_LANGUAGE, &self.content_language),
+            (header::CONTENT_ENCODING, &self.content_encoding),
+            (header::EXPIRES, &self.expires),
+        ]
+    }
+
+    fn fields_mut(&mut self) -> [(HeaderName, &mut Option<String>); 4] {
+        [
+            (header::CACHE_CONTROL, &mut self.cache_control),
+            (header::CONTENT_LANGUAGE, &mut self.content_language),
+            (header::CONTENT_ENCODING, &mut self.content_encoding),
+            (header::EXPIRES, &mut self.expires),
+        ]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields().iter().all(|(_, value)| value.is_none())
+    }
+
+    /// Read the headers set by an upload, from `X-Blob-Response-` followed by the name of the header
+    pub fn from_upload(headers: &header::HeaderMap) -> Result<Self, String> {
+        let mut res = Self::default();
+        for (name, value) in res.fields_mut() {
+            let upload_name = format!("{}{}", RESPONSE_HEADER_PREFIX, name.as_str());
+            if let Some(v) = headers.get(upload_name.as_str()) {
+                match v.to_str() {
+                    Ok(v) => *value = Some(v.to_string()),
+                    Err(_) => return Err(format!("Invalid {}", name)),
+                }
+            }
+        }
+        res.validate()?;
+        Ok(res)
+    }
+
+    /// Check every header can be sent
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in self.fields() {
+            if let Some(value) = value
+                && (value.is_empty() || HeaderValue::from_str(value).is_err())
+            {
+                return Err(format!("Invalid {}", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// These headers, with any that aren't set taken from `defaults`
+    pub fn or(&self, defaults: &ResponseHeaders) -> ResponseHeaders {
+        ResponseHeaders {
+            cache_control: self
+                .cache_control
+                .clone()
+                .or_else(|| defaults.cache_control.clone()),
+            content_language: self
+                .content_language
+                .clone()
+                .or_else(|| defaults.content_language.clone()),
+            content_encoding: self
+                .content_encoding
+                .clone()
+                .or_else(|| defaults.content_encoding.clone()),
+            expires: self.expires.clone().or_else(|| defaults.expires.clone()),
+        }
+    }
+
+    /// Add the headers to a response
+    /// Blobs that can stop being available mustn't outlive that in a cache, so `cacheable` false overrides caching
+    pub fn apply(&self, response: &mut HttpResponseBuilder, cacheable: bool) {
+        for (name, value) in self.fields() {
+            let Some(value) = value else {
+                continue;
+            };
+            if !cacheable && (name == header::CACHE_CONTROL || name == header::EXPIRES) {
+                continue;
+            }
+            response.insert_header((name, value.as_str()));
+        }
+
+        if !cacheable {
+            response.insert_header((header::CACHE_CONTROL, "no-store"));
+        }
+    }
+}
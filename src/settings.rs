@@ -1,3 +1,6 @@
+use crate::bandwidth::BandwidthSettings;
+use crate::rate_limit::RateLimitSettings;
+use actix_web::HttpRequest;
 use anyhow::Context;
 use std::env;
 
@@ -13,6 +16,30 @@ pub fn get_host_domain() -> String {
     env::var("HOST_DOMAIN").unwrap_or_else(|_| format!("{}:{}", get_host_ip(), get_host_port()))
 }
 
+/// What to do with keys given in the query string, where they end up in logs, proxies and browser history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryAuthPolicy {
+    Allow,
+    /// Accept them, but log a warning
+    Warn,
+    /// Ignore them, as if no key was given
+    Reject,
+}
+
+impl QueryAuthPolicy {
+    fn from_env() -> anyhow::Result<Self> {
+        match env::var("QUERY_AUTH").as_deref() {
+            Err(_) | Ok("warn") => Ok(QueryAuthPolicy::Warn),
+            Ok("allow") => Ok(QueryAuthPolicy::Allow),
+            Ok("reject") => Ok(QueryAuthPolicy::Reject),
+            Ok(other) => Err(anyhow::anyhow!(
+                "Invalid QUERY_AUTH {}, expected allow, warn or reject",
+                other
+            )),
+        }
+    }
+}
+
 pub struct AppSettings {
     pub storage_root: String,
 
@@ -21,6 +48,22 @@ pub struct AppSettings {
 
     /// Key required to upload new files to a bucket
     pub bucket_upload_key: String,
+
+    /// Secret used to sign API tokens and presigned URLs, both are disabled without it
+    pub token_secret: Option<String>,
+
+    /// How to treat `?auth=` keys, the `Authorization` header should be used instead
+    pub query_auth: QueryAuthPolicy,
+
+    /// Limits on how often clients can make requests, see [crate::rate_limit]
+    pub rate_limit: RateLimitSettings,
+
+    /// Caps on download bandwidth, see [crate::bandwidth]
+    pub bandwidth: BandwidthSettings,
+
+    /// Base of the URLs handed out for presigned uploads and share links, e.g. `https://files.example.com`
+    /// Without it they are made from the scheme and host the request was made to
+    pub public_url: Option<String>,
 }
 
 impl AppSettings {
@@ -31,6 +74,45 @@ impl AppSettings {
                 .context("No bucket create key specified")?,
             bucket_upload_key: env::var("BUCKET_UPLOAD_KEY")
                 .context("No bucket upload key specified")?,
+            token_secret: env::var("TOKEN_SECRET").ok().filter(|s| !s.is_empty()),
+            query_auth: QueryAuthPolicy::from_env()?,
+            rate_limit: RateLimitSettings::from_env()?,
+            bandwidth: BandwidthSettings::from_env()?,
+            public_url: env::var("PUBLIC_URL")
+                .ok()
+                .map(|u| u.trim_end_matches('/').to_string())
+                .filter(|u| !u.is_empty()),
         })
     }
+
+    /// Settings for tests, with the given token secret and nothing else turned on
+    #[cfg(test)]
+    pub fn for_test(token_secret: Option<&str>) -> Self {
+        Self {
+            storage_root: "./storage_root".to_string(),
+            bucket_creation_key: "create-key".to_string(),
+            bucket_upload_key: "upload-key".to_string(),
+            token_secret: token_secret.map(str::to_string),
+            query_auth: QueryAuthPolicy::Warn,
+            rate_limit: RateLimitSettings {
+                groups: Default::default(),
+                lockout_failures: None,
+                lockout_duration: Default::default(),
+                trust_proxy: false,
+            },
+            bandwidth: BandwidthSettings::default(),
+            public_url: None,
+        }
+    }
+
+    /// The full URL of `path` on this server, as given to the client making `req`
+    pub fn public_url(&self, req: &HttpRequest, path: &str) -> String {
+        match &self.public_url {
+            Some(base) => format!("{}{}", base, path),
+            None => {
+                let info = req.connection_info();
+                format!("{}://{}{}", info.scheme(), info.host(), path)
+            }
+        }
+    }
 }
@@ -0,0 +1,104 @@
+//! Share links
+//! A share link gives access to a single blob through a short random URL, `/s/<id>`. Links can expire, be limited to a
+//! number of downloads and be protected by a password, and are stored in the metadata DB so they can be revoked
+
+use crate::access_key::PasswordHash;
+use crate::metadata::{BlobMetadata, DownloadOutcome};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use rand::distr::Alphanumeric;
+use serde::{Deserialize, Serialize};
+
+/// Start of the path of every share link, followed by its id
+pub const SHARE_PATH_PREFIX: &str = "/s/";
+
+/// Length of the random id of a share link
+const SHARE_ID_LEN: usize = 10;
+
+/// Create a new random share id
+pub fn generate_share_id() -> String {
+    rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(SHARE_ID_LEN)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareLink {
+    pub id: String,
+    /// Metadata key of the shared blob
+    pub blob_key: Vec<u8>,
+    /// Tenant the blob belongs to, `None` for the default namespace
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub bucket_name: String,
+    pub file_name: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_downloads: Option<u32>,
+    #[serde(default)]
+    pub download_count: u32,
+    pub password_hash: Option<PasswordHash>,
+}
+
+impl ShareLink {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|e| e <= Utc::now())
+    }
+
+    pub fn download_limit_reached(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max| self.download_count >= max)
+    }
+
+    /// Is this link for the blob with `meta`
+    /// Links aren't removed when their blob is, so a blob created after the link is a later upload under the same name
+    pub fn is_for(&self, meta: &BlobMetadata) -> bool {
+        meta.created_at.is_none_or(|c| c <= self.created_at)
+    }
+}
+
+/// Result of trying to download through a share link
+pub enum ShareOutcome {
+    /// The link is usable, with the outcome of downloading its blob
+    Blob(DownloadOutcome),
+    Missing,
+    Expired,
+    LimitReached,
+}
+
+/// Page asking for the password of a protected share link
+pub fn password_page(id: &str, wrong_password: bool, download: bool) -> String {
+    let error = if wrong_password {
+        r#"<p class="error">Wrong password</p>"#
+    } else {
+        ""
+    };
+    // Keep asking for an attachment once the password is given
+    let query = if download { "?download=1" } else { "" };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Password required</title>
+<style>
+body {{ font-family: sans-serif; display: flex; justify-content: center; margin-top: 20vh; }}
+.error {{ color: #b00; }}
+</style>
+</head>
+<body>
+<form method="post" action="/s/{id}{query}">
+<p>This file is password protected</p>
+{error}
+<input type="password" name="password" autofocus required>
+<button type="submit">Download</button>
+</form>
+</body>
+</html>
+"#
+    )
+}
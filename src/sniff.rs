@@ -0,0 +1,283 @@
+//! Content type detection
+//! Uploads that don't declare a content type get one from the magic bytes at the start of their content, or failing
+//! that from the extension of their name. Only magic bytes are trusted enough to say a declared type is wrong.
+//! Detected markup is never shown inline, as nobody chose to serve it as a page
+
+/// How much of the start of an upload is looked at
+pub const SNIFF_LEN: usize = 512;
+
+/// Content type of anything that isn't recognised and isn't text
+pub const FALLBACK_BINARY: &str = "application/octet-stream";
+
+/// Content type of anything that isn't recognised but is valid UTF-8
+pub const FALLBACK_TEXT: &str = "text/plain";
+
+/// Content types a browser runs scripts in when shown inline
+const ACTIVE: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "application/xml",
+    "text/xml",
+];
+
+/// Magic bytes at the start of content, `None` matches any byte
+const MAGIC: &[(&[Option<u8>], &str)] = &[
+    (&bytes(b"\x89PNG\r\n\x1a\n"), "image/png"),
+    (&bytes(b"\xff\xd8\xff"), "image/jpeg"),
+    (&bytes(b"GIF87a"), "image/gif"),
+    (&bytes(b"GIF89a"), "image/gif"),
+    (&riff(b"WEBP"), "image/webp"),
+    (&riff(b"WAVE"), "audio/wav"),
+    (&riff(b"AVI "), "video/x-msvideo"),
+    (&ftyp(b"avif"), "image/avif"),
+    (&ftyp(b"heic"), "image/heic"),
+    (&ftyp(b"qt  "), "video/quicktime"),
+    (&ftyp(b"isom"), "video/mp4"),
+    (&ftyp(b"mp41"), "video/mp4"),
+    (&ftyp(b"mp42"), "video/mp4"),
+    (&ftyp(b"M4A "), "audio/mp4"),
+    (&bytes(b"\x00\x00\x01\x00"), "image/vnd.microsoft.icon"),
+    (&bytes(b"%PDF-"), "application/pdf"),
+    (&bytes(b"PK\x03\x04"), "application/zip"),
+    (&bytes(b"\x1f\x8b"), "application/gzip"),
+    (&bytes(b"7z\xbc\xaf\x27\x1c"), "application/x-7z-compressed"),
+    (&bytes(b"\x00asm"), "application/wasm"),
+    (&bytes(b"ID3"), "audio/mpeg"),
+    (&bytes(b"OggS"), "audio/ogg"),
+    (&bytes(b"fLaC"), "audio/flac"),
+    (&bytes(b"\x1a\x45\xdf\xa3"), "video/webm"),
+];
+
+/// Content types of file extensions
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("bmp", "image/bmp"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("svg", "image/svg+xml"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("7z", "application/x-7z-compressed"),
+    ("tar", "application/x-tar"),
+    ("wasm", "application/wasm"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("js", "text/javascript"),
+    ("css", "text/css"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("txt", "text/plain"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("m4a", "audio/mp4"),
+    ("mp4", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("webm", "video/webm"),
+    ("avi", "video/x-msvideo"),
+];
+
+const fn bytes<const N: usize>(magic: &[u8; N]) -> [Option<u8>; N] {
+    let mut out = [None; N];
+    let mut i = 0;
+    while i < N {
+        out[i] = Some(magic[i]);
+        i += 1;
+    }
+    out
+}
+
+/// `RIFF<size><kind>`
+const fn riff(kind: &[u8; 4]) -> [Option<u8>; 12] {
+    let mut out = [None; 12];
+    let riff = b"RIFF";
+    let mut i = 0;
+    while i < 4 {
+        out[i] = Some(riff[i]);
+        out[i + 8] = Some(kind[i]);
+        i += 1;
+    }
+    out
+}
+
+/// ISO base media files, `<size>ftyp<brand>`
+const fn ftyp(brand: &[u8; 4]) -> [Option<u8>; 12] {
+    let mut out = [None; 12];
+    let ftyp = b"ftyp";
+    let mut i = 0;
+    while i < 4 {
+        out[i + 4] = Some(ftyp[i]);
+        out[i + 8] = Some(brand[i]);
+        i += 1;
+    }
+    out
+}
+
+/// The content type given by the magic bytes at the start of `head`
+pub fn from_magic(head: &[u8]) -> Option<&'static str> {
+    if let Some(found) = MAGIC.iter().find(|(magic, _)| {
+        head.len() >= magic.len()
+            && magic
+                .iter()
+                .zip(head)
+                .all(|(m, b)| m.is_none_or(|m| m == *b))
+    }) {
+        return Some(found.1);
+    }
+
+    // Markup has no fixed magic, only a telltale start once whitespace is skipped
+    let text = std::str::from_utf8(head)
+        .or_else(|e| std::str::from_utf8(&head[..e.valid_up_to()]))
+        .ok()?
+        .trim_start_matches('\u{feff}')
+        .trim_start();
+    let starts_with = |prefix: &str| {
+        text.get(..prefix.len())
+            .is_some_and(|t| t.eq_ignore_ascii_case(prefix))
+    };
+
+    if starts_with("<!doctype html") || starts_with("<html") {
+        Some("text/html")
+    } else if starts_with("<svg") {
+        Some("image/svg+xml")
+    } else if starts_with("<?xml") {
+        // SVGs usually start with an XML declaration too
+        if text.contains("<svg") {
+            Some("image/svg+xml")
+        } else {
+            Some("application/xml")
+        }
+    } else {
+        None
+    }
+}
+
+/// The content type given by the extension of `file_name`
+pub fn from_extension(file_name: &str) -> Option<&'static str> {
+    let (_, extension) = file_name.rsplit_once('.')?;
+    EXTENSIONS
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, ct)| *ct)
+}
+
+/// Best guess at the content type of a blob called `file_name`, from the start of its content
+/// `complete` is whether `head` is the whole content, otherwise it may end part way through a UTF-8 character
+pub fn detect(head: &[u8], file_name: &str, complete: bool) -> &'static str {
+    if let Some(ct) = from_magic(head).or_else(|| from_extension(file_name)) {
+        return ct;
+    }
+
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // Only an incomplete character at the very end is allowed
+        Err(e) => !complete && e.error_len().is_none(),
+    };
+    if is_text {
+        FALLBACK_TEXT
+    } else {
+        FALLBACK_BINARY
+    }
+}
+
+/// Can content of this type run scripts when a browser shows it inline
+pub fn is_active(content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or_default().trim();
+    ACTIVE
+        .iter()
+        .any(|ct| ct.eq_ignore_ascii_case(content_type))
+}
+
+/// Does a declared content type disagree with the magic bytes of the content
+/// Parameters such as `charset` are ignored, and content without recognisable magic bytes never disagrees
+pub fn is_mismatch(declared: &str, head: &[u8]) -> bool {
+    let Some(sniffed) = from_magic(head) else {
+        return false;
+    };
+    let declared = declared.split(';').next().unwrap_or_default().trim();
+    !declared.eq_ignore_ascii_case(sniffed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_magic_bytes() {
+        assert_eq!(
+            from_magic(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(from_magic(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(from_magic(b"\0\0\0\x18ftypmp42"), Some("video/mp4"));
+        assert_eq!(from_magic(b"%PDF-1.7"), Some("application/pdf"));
+        // Too short to be sure
+        assert_eq!(from_magic(b"\x89PNG"), None);
+        assert_eq!(from_magic(b"hello"), None);
+    }
+
+    #[test]
+    fn detects_markup_after_whitespace_and_bom() {
+        assert_eq!(
+            from_magic("\u{feff}  \n<!DOCTYPE html><html>".as_bytes()),
+            Some("text/html")
+        );
+        assert_eq!(from_magic(b"<HTML><body>"), Some("text/html"));
+        assert_eq!(
+            from_magic(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            from_magic(b"<?xml version=\"1.0\"?><feed>"),
+            Some("application/xml")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_extension_then_text_or_binary() {
+        assert_eq!(
+            detect(b"body { color: red }", "style.CSS", true),
+            "text/css"
+        );
+        assert_eq!(detect(b"just some words", "notes", true), FALLBACK_TEXT);
+        assert_eq!(detect(b"\xff\xfe\x00\x01", "data", true), FALLBACK_BINARY);
+        // Magic bytes win over the extension
+        assert_eq!(detect(b"GIF89a....", "picture.png", true), "image/gif");
+    }
+
+    #[test]
+    fn cut_off_utf8_is_only_text_when_incomplete() {
+        let head = "caf\u{e9}".as_bytes();
+        let cut = &head[..head.len() - 1];
+        assert_eq!(detect(cut, "notes", false), FALLBACK_TEXT);
+        assert_eq!(detect(cut, "notes", true), FALLBACK_BINARY);
+    }
+
+    #[test]
+    fn mismatch_needs_magic_bytes_that_disagree() {
+        assert!(is_mismatch("image/png", b"GIF89a...."));
+        assert!(is_mismatch("image/png", b"<html><script>"));
+        assert!(!is_mismatch("IMAGE/GIF; charset=binary", b"GIF89a...."));
+        assert!(!is_mismatch("image/png", b"no magic here"));
+    }
+
+    #[test]
+    fn markup_is_active() {
+        assert!(is_active("text/html"));
+        assert!(is_active("text/html; charset=utf-8"));
+        assert!(is_active("Image/SVG+XML"));
+        assert!(is_active("application/xml"));
+        assert!(!is_active("text/plain"));
+        assert!(!is_active("image/png"));
+    }
+}
@@ -0,0 +1,157 @@
+//! Multi-tenant namespaces
+//! A tenant owns its own set of buckets, stored under `<storage root>/tenants/<name>`. As metadata is keyed by path,
+//! the buckets, blobs and configs of one tenant can never be reached through another. Requests are routed to a tenant
+//! either by a `/t/<name>/` path prefix, or by a host name registered for the tenant; everything else goes to the
+//! default namespace at the storage root.
+//! Each tenant has its own admin key, which can do anything within the tenant but nothing outside it
+
+use crate::access_key::AccessKeyHash;
+use crate::metadata::MetadataManager;
+use crate::path::PathManager;
+use crate::quota::Quota;
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::Uri;
+use actix_web::web::Data;
+use actix_web::{Error as AWError, FromRequest, HttpMessage, HttpRequest, error};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::future::{Ready, ready};
+use std::ops::Deref;
+
+/// Path prefix that routes a request to a tenant, followed by the tenant's name
+pub const TENANT_PATH_PREFIX: &str = "/t/";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    /// Key that can do anything within this tenant
+    pub admin_key: AccessKeyHash,
+    /// Host names that route to this tenant
+    #[serde(default)]
+    pub hosts: BTreeSet<String>,
+    pub created_at: DateTime<Utc>,
+    /// Limits on what can be stored across all of the tenant's buckets
+    #[serde(default)]
+    pub quota: Quota,
+}
+
+/// The tenant a request was routed to, stored in the request's extensions
+#[derive(Debug, Clone)]
+pub struct RequestTenant(pub String);
+
+/// Tenant names are used as directory names and in URLs, so are kept to lowercase letters, numbers and '-'
+pub fn validate_tenant_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > 63 {
+        return Err("Tenant name must be between 1 and 63 characters".to_string());
+    }
+
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err("Tenant name can only contain lowercase letters, numbers and '-'".to_string());
+    }
+
+    Ok(())
+}
+
+/// Normalise a host name for matching, dropping the port and case
+pub fn normalize_host(host: &str) -> String {
+    host.split(':')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// Find the tenant a request is for and route it there, before it's matched to a handler
+/// A `/t/<name>/` prefix is stripped from the path so the usual routes match, otherwise the host is looked up
+pub fn route_tenant(req: &mut ServiceRequest) {
+    if let Some((tenant, rest)) = req
+        .path()
+        .strip_prefix(TENANT_PATH_PREFIX)
+        .and_then(|p| p.split_once('/'))
+    {
+        let tenant = tenant.to_string();
+        let path_and_query = match req.query_string() {
+            "" => format!("/{}", rest),
+            query => format!("/{}?{}", rest, query),
+        };
+
+        let mut parts = req.head().uri.clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        let Ok(uri) = Uri::from_parts(parts) else {
+            tracing::warn!("Failed to strip tenant prefix from {}", req.path());
+            return;
+        };
+
+        req.match_info_mut().get_mut().update(&uri);
+        req.head_mut().uri = uri;
+        req.extensions_mut().insert(RequestTenant(tenant));
+        return;
+    }
+
+    let Some(metadata) = req.app_data::<Data<MetadataManager>>() else {
+        return;
+    };
+
+    let host = normalize_host(req.connection_info().host());
+    match metadata.get_tenant_by_host(&host) {
+        Ok(Some(tenant)) => {
+            req.extensions_mut().insert(RequestTenant(tenant.name));
+        }
+        Ok(None) => {}
+        Err(e) => tracing::warn!("Failed to look up tenant of host {}", e),
+    }
+}
+
+/// The tenant a request was routed to, `None` for the default namespace
+pub fn request_tenant(req: &HttpRequest) -> Option<String> {
+    req.extensions().get::<RequestTenant>().map(|t| t.0.clone())
+}
+
+/// The [PathManager] for the namespace a request was routed to
+/// Handlers take this instead of the global [PathManager], so they can only reach buckets of the request's tenant.
+/// Requests for a tenant that doesn't exist are answered with 404
+pub struct TenantPaths(PathManager);
+
+impl Deref for TenantPaths {
+    type Target = PathManager;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TenantPaths {
+    fn from_req(req: &HttpRequest) -> Result<Self, AWError> {
+        let (Some(paths), Some(metadata)) = (
+            req.app_data::<Data<PathManager>>(),
+            req.app_data::<Data<MetadataManager>>(),
+        ) else {
+            return Err(error::ErrorInternalServerError("Missing app data"));
+        };
+
+        let Some(tenant) = request_tenant(req) else {
+            return Ok(Self(PathManager::clone(paths)));
+        };
+
+        match metadata.get_tenant(&tenant) {
+            Ok(Some(_)) => Ok(Self(paths.for_tenant(&tenant))),
+            Ok(None) => Err(error::ErrorNotFound("Unknown tenant")),
+            Err(e) => {
+                tracing::warn!("Failed to get tenant {}", e);
+                Err(error::ErrorInternalServerError("Failed to get tenant"))
+            }
+        }
+    }
+}
+
+impl FromRequest for TenantPaths {
+    type Error = AWError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(Self::from_req(req))
+    }
+}
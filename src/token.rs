@@ -0,0 +1,293 @@
+//! Scoped, expiring API tokens
+//! Tokens are signed with the server's token secret so they can be checked without a DB lookup, other than the list of
+//! revoked tokens. They look like `cst_<claims>.<signature>`, both parts being unpadded URL-safe base64
+
+use crate::metadata::MetadataManager;
+use crate::settings::AppSettings;
+use actix_web::HttpRequest;
+use actix_web::http::header;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+pub const TOKEN_PREFIX: &str = "cst_";
+
+/// Bucket name that matches every bucket
+pub const ANY_BUCKET: &str = "*";
+
+/// Things a token can be allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenOp {
+    Read,
+    Upload,
+    Delete,
+    List,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenClaims {
+    /// Unique id of this token, used to revoke it
+    pub id: String,
+    /// Tenant whose buckets this token is for, `None` for the default namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Buckets this token can be used on, [ANY_BUCKET] allows all of them
+    pub buckets: BTreeSet<String>,
+    /// If not empty, the token can only be used on files starting with one of these
+    #[serde(default)]
+    pub prefixes: BTreeSet<String>,
+    pub ops: BTreeSet<TokenOp>,
+    pub expires_at: DateTime<Utc>,
+    pub issued_at: DateTime<Utc>,
+}
+
+impl TokenClaims {
+    pub fn new(
+        tenant: Option<String>,
+        buckets: BTreeSet<String>,
+        prefixes: BTreeSet<String>,
+        ops: BTreeSet<TokenOp>,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        let id: [u8; 16] = rand::rng().random();
+        Self {
+            id: URL_SAFE_NO_PAD.encode(id),
+            tenant,
+            buckets,
+            prefixes,
+            ops,
+            expires_at,
+            issued_at: Utc::now(),
+        }
+    }
+
+    /// Does this token allow `op` on `file` in `bucket` of `tenant`, `file` is `None` for operations on the bucket
+    /// itself
+    pub fn allows(
+        &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        file: Option<&str>,
+        op: TokenOp,
+    ) -> bool {
+        if self.expires_at <= Utc::now() || !self.ops.contains(&op) {
+            return false;
+        }
+
+        if self.tenant.as_deref() != tenant {
+            return false;
+        }
+
+        if !self.buckets.contains(ANY_BUCKET) && !self.buckets.contains(bucket) {
+            return false;
+        }
+
+        match file {
+            _ if self.prefixes.is_empty() => true,
+            Some(file) => self.prefixes.iter().any(|p| file.starts_with(p.as_str())),
+            // Prefix limited tokens can't do anything to a whole bucket
+            None => false,
+        }
+    }
+}
+
+/// HMAC keyed with the server secret, shared by tokens and presigned URLs
+pub fn mac(secret: &str) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take keys of any size")
+}
+
+/// Sign claims to make a token
+pub fn sign_token(secret: &str, claims: &TokenClaims) -> anyhow::Result<String> {
+    let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims)?);
+    let mut mac = mac(secret);
+    mac.update(claims.as_bytes());
+    let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+    Ok(format!("{}{}.{}", TOKEN_PREFIX, claims, signature))
+}
+
+/// Check the signature of a token and get its claims, this doesn't check expiry or revocation
+pub fn decode_token(secret: &str, token: &str) -> Option<TokenClaims> {
+    let (claims, signature) = token.strip_prefix(TOKEN_PREFIX)?.split_once('.')?;
+
+    let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+    let mut mac = mac(secret);
+    mac.update(claims.as_bytes());
+    mac.verify_slice(&signature).ok()?;
+
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+/// Get the token from the `Authorization: Bearer` header of a request, if there is one
+pub fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Check if a request has a valid, unrevoked token that allows `op` on `file` in `bucket` of `tenant`
+pub fn request_allows(
+    req: &HttpRequest,
+    settings: &AppSettings,
+    metadata: &MetadataManager,
+    tenant: Option<&str>,
+    bucket: &str,
+    file: Option<&str>,
+    op: TokenOp,
+) -> bool {
+    let Some(secret) = &settings.token_secret else {
+        return false;
+    };
+
+    let Some(claims) = bearer_token(req).and_then(|t| decode_token(secret, t)) else {
+        return false;
+    };
+
+    if !claims.allows(tenant, bucket, file, op) {
+        tracing::warn!("Token {} doesn't allow {:?} on {}", claims.id, op, bucket);
+        return false;
+    }
+
+    match metadata.is_token_revoked(&claims.id) {
+        Ok(false) => true,
+        Ok(true) => {
+            tracing::warn!("Attempt to use revoked token {}", claims.id);
+            false
+        }
+        Err(e) => {
+            tracing::warn!("Failed to check token revocation {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use chrono::Duration;
+
+    const SECRET: &str = "secret";
+
+    fn claims(expires_at: DateTime<Utc>) -> TokenClaims {
+        TokenClaims::new(
+            None,
+            BTreeSet::from(["photos".to_string()]),
+            BTreeSet::from(["public/".to_string()]),
+            BTreeSet::from([TokenOp::Read]),
+            expires_at,
+        )
+    }
+
+    fn request(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_http_request()
+    }
+
+    #[test]
+    fn signed_token_decodes_to_its_claims() {
+        let claims = claims(Utc::now() + Duration::hours(1));
+        let token = sign_token(SECRET, &claims).unwrap();
+
+        let decoded = decode_token(SECRET, &token).unwrap();
+        assert_eq!(decoded.id, claims.id);
+        assert_eq!(decoded.buckets, claims.buckets);
+        assert_eq!(decoded.ops, claims.ops);
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let token = sign_token(SECRET, &claims(Utc::now() + Duration::hours(1))).unwrap();
+        assert!(decode_token("other secret", &token).is_none());
+
+        let (payload, signature) = token.split_once('.').unwrap();
+        let mut forged = claims(Utc::now() + Duration::days(365));
+        forged.ops.insert(TokenOp::Delete);
+        let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert!(
+            decode_token(
+                SECRET,
+                &format!("{}{}.{}", TOKEN_PREFIX, forged_payload, signature)
+            )
+            .is_none()
+        );
+        assert!(decode_token(SECRET, &format!("{}.{}A", payload, signature)).is_none());
+    }
+
+    #[test]
+    fn token_only_allows_its_scope() {
+        let claims = claims(Utc::now() + Duration::hours(1));
+
+        assert!(claims.allows(None, "photos", Some("public/cat.png"), TokenOp::Read));
+        assert!(!claims.allows(None, "photos", Some("private/cat.png"), TokenOp::Read));
+        assert!(!claims.allows(None, "photos", Some("public/cat.png"), TokenOp::Delete));
+        assert!(!claims.allows(None, "videos", Some("public/cat.png"), TokenOp::Read));
+        assert!(!claims.allows(
+            Some("acme"),
+            "photos",
+            Some("public/cat.png"),
+            TokenOp::Read
+        ));
+        // Prefix limited tokens can't act on the whole bucket
+        assert!(!claims.allows(None, "photos", None, TokenOp::Read));
+    }
+
+    #[test]
+    fn expired_token_allows_nothing() {
+        let claims = claims(Utc::now() - Duration::seconds(1));
+        assert!(!claims.allows(None, "photos", Some("public/cat.png"), TokenOp::Read));
+    }
+
+    #[test]
+    fn revoked_token_is_refused() {
+        let settings = AppSettings::for_test(Some(SECRET));
+        let metadata = MetadataManager::temporary().unwrap();
+        let claims = claims(Utc::now() + Duration::hours(1));
+        let req = request(&sign_token(SECRET, &claims).unwrap());
+        let allows = || {
+            request_allows(
+                &req,
+                &settings,
+                &metadata,
+                None,
+                "photos",
+                Some("public/cat.png"),
+                TokenOp::Read,
+            )
+        };
+
+        assert!(allows());
+        metadata
+            .revoke_token(&claims.id, claims.expires_at)
+            .unwrap();
+        assert!(!allows());
+    }
+
+    #[test]
+    fn tokens_are_refused_without_a_secret() {
+        let settings = AppSettings::for_test(None);
+        let metadata = MetadataManager::temporary().unwrap();
+        let req = request(&sign_token(SECRET, &claims(Utc::now() + Duration::hours(1))).unwrap());
+
+        assert!(!request_allows(
+            &req,
+            &settings,
+            &metadata,
+            None,
+            "photos",
+            Some("public/cat.png"),
+            TokenOp::Read,
+        ));
+    }
+}
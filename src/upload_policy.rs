@@ -0,0 +1,138 @@
+//! Per-bucket upload policies
+//! A bucket can limit the size, content type and file extension of uploads, and require them to have some headers.
+//! Everything but the size is checked before any of the upload is written, the size is checked as it is streamed
+
+use actix_web::http::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadPolicy {
+    /// Largest blob that can be uploaded, in bytes
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// Content types uploads can have, `type/*` allows a whole family. Empty allows any
+    #[serde(default)]
+    pub allowed_content_types: BTreeSet<String>,
+    /// File extensions uploads can have, without the dot. Empty allows any
+    #[serde(default)]
+    pub allowed_extensions: BTreeSet<String>,
+    /// Headers every upload must have
+    #[serde(default)]
+    pub required_headers: BTreeSet<String>,
+    /// Reject uploads whose declared content type disagrees with their content
+    #[serde(default)]
+    pub reject_content_type_mismatch: bool,
+}
+
+/// Ways an upload can break a bucket's [UploadPolicy]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    ContentType(String),
+    ContentTypeMismatch,
+    Extension,
+    MissingHeader(String),
+    TooLarge(u64),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::ContentType(ct) => {
+                write!(f, "Content type {} is not allowed in this bucket", ct)
+            }
+            PolicyViolation::ContentTypeMismatch => {
+                write!(f, "Content doesn't match its declared content type")
+            }
+            PolicyViolation::Extension => write!(f, "File extension is not allowed in this bucket"),
+            PolicyViolation::MissingHeader(name) => {
+                write!(f, "Uploads to this bucket must have the {} header", name)
+            }
+            PolicyViolation::TooLarge(max) => {
+                write!(f, "Uploads to this bucket can be at most {} bytes", max)
+            }
+        }
+    }
+}
+
+/// The MIME type of a content type, lowercase and without parameters
+fn essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+impl UploadPolicy {
+    /// Can blobs in the bucket have `content_type`, parameters such as `charset` are ignored
+    pub fn allows_content_type(&self, content_type: &str) -> bool {
+        if self.allowed_content_types.is_empty() {
+            return true;
+        }
+
+        let content_type = essence(content_type);
+        let family = content_type.split('/').next().unwrap_or_default();
+        self.allowed_content_types.iter().any(|allowed| {
+            let allowed = essence(allowed);
+            match allowed.strip_suffix("/*") {
+                Some(allowed_family) => allowed_family == family,
+                None => allowed == content_type,
+            }
+        })
+    }
+
+    fn allows_extension(&self, file_name: &str) -> bool {
+        if self.allowed_extensions.is_empty() {
+            return true;
+        }
+
+        let Some((_, extension)) = file_name.rsplit_once('.') else {
+            return false;
+        };
+        self.allowed_extensions.iter().any(|allowed| {
+            allowed
+                .trim_start_matches('.')
+                .eq_ignore_ascii_case(extension)
+        })
+    }
+
+    /// Check everything about an upload that is known before its content
+    /// `content_type` is `None` if it wasn't declared, and will be detected from the content
+    pub fn check_upload(
+        &self,
+        headers: &HeaderMap,
+        file_name: &str,
+        content_type: Option<&str>,
+    ) -> Result<(), PolicyViolation> {
+        if let Some(missing) = self
+            .required_headers
+            .iter()
+            .find(|name| !headers.contains_key(name.as_str()))
+        {
+            return Err(PolicyViolation::MissingHeader(missing.clone()));
+        }
+
+        if let Some(content_type) = content_type
+            && !self.allows_content_type(content_type)
+        {
+            return Err(PolicyViolation::ContentType(content_type.to_string()));
+        }
+
+        if !self.allows_extension(file_name) {
+            return Err(PolicyViolation::Extension);
+        }
+
+        Ok(())
+    }
+
+    /// Check the size of an upload, as it is streamed
+    pub fn check_size(&self, size: u64) -> Result<(), PolicyViolation> {
+        match self.max_size {
+            Some(max) if size > max => Err(PolicyViolation::TooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Named user accounts
+//! Users log in with a password to get a session token, which is sent as `Authorization: Bearer css_...`. What they can
+//! do is decided by their [Role], either given for every bucket or granted per bucket
+
+use crate::access_key::PasswordHash;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+pub const SESSION_PREFIX: &str = "css_";
+
+/// What a user can do to a bucket, each role can do everything the ones before it can
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// Can read private blobs
+    Reader,
+    /// Can also upload, modify and delete blobs
+    Writer,
+    /// Can also manage the bucket, its keys and tokens
+    BucketOwner,
+    /// Can do anything, including creating buckets and managing users. Only meaningful as a user's global role
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub username: String,
+    pub password_hash: PasswordHash,
+    /// Role in every bucket
+    pub role: Option<Role>,
+    /// Roles in single buckets, keyed by bucket name, or `<tenant>/<bucket name>` for buckets of a tenant
+    #[serde(default)]
+    pub grants: BTreeMap<String, Role>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    /// The highest role this user has in the given bucket of `tenant`
+    /// A user's global role only applies to the default namespace, tenants' buckets need a grant
+    pub fn role_in(&self, tenant: Option<&str>, bucket_name: &str) -> Option<Role> {
+        match tenant {
+            Some(tenant) => self
+                .grants
+                .get(&format!("{}/{}", tenant, bucket_name))
+                .copied(),
+            None => self.role.max(self.grants.get(bucket_name).copied()),
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Some(Role::Admin)
+    }
+}
+
+/// A logged in session, stored under the hash of its token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub username: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Create a new random session token
+pub fn generate_session_token() -> String {
+    let token: [u8; 32] = rand::rng().random();
+    format!("{}{}", SESSION_PREFIX, URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Key a session is stored under, so a leaked database doesn't leak usable tokens
+/// Tokens are long and random so an unsalted hash is enough, and lets sessions be looked up directly
+pub fn session_key(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Usernames are kept simple so they can be used in paths
+pub fn validate_username(username: &str) -> Result<(), String> {
+    if username.is_empty() || username.len() > 64 {
+        return Err("Username must be between 1 and 64 characters".to_string());
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err("Username can only contain letters, numbers, '-', '_' and '.'".to_string());
+    }
+
+    Ok(())
+}
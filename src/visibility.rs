@@ -0,0 +1,73 @@
+//! Public and private blobs
+//! Private blobs can only be read by requests with [crate::auth::Permission::Read] on them, such as the bucket's read
+//! key, the blob's access key, a read token or a presigned URL. To everyone else they look like they don't exist, so
+//! requests for them get a 404 rather than a 403
+
+use crate::metadata::{BlobMetadata, MetadataManager};
+use crate::path::{BucketPath, PathExists, PathManager};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Header used to set the visibility of a blob on upload
+pub const VISIBILITY_HEADER: &str = "X-Blob-Visibility";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Private,
+}
+
+impl Visibility {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "public" => Some(Visibility::Public),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+}
+
+/// Is a blob private, either set on the blob itself or inherited from its bucket
+pub fn is_private(
+    metadata: &MetadataManager,
+    bucket: &BucketPath<PathExists>,
+    meta: &BlobMetadata,
+) -> anyhow::Result<bool> {
+    let visibility = match meta.visibility {
+        Some(v) => v,
+        None => metadata
+            .get_bucket_config(bucket)?
+            .map(|c| c.visibility)
+            .unwrap_or_default(),
+    };
+    Ok(visibility == Visibility::Private)
+}
+
+/// Is the blob stored under `key` private, anything that can't be looked up counts as private
+pub fn is_private_key(
+    paths: &PathManager,
+    metadata: &MetadataManager,
+    key: &[u8],
+    meta: &BlobMetadata,
+) -> bool {
+    if let Some(v) = meta.visibility {
+        return v == Visibility::Private;
+    }
+
+    paths
+        .locate_blob(Path::new(OsStr::from_bytes(key)))
+        .and_then(|(bucket_name, _)| paths.get_bucket(Path::new(&bucket_name)))
+        .is_none_or(|bucket| is_private(metadata, &bucket, meta).unwrap_or(true))
+}
+
+/// Is the blob stored under `key` private, looking up its metadata, blobs without metadata count as private
+pub fn is_private_blob(paths: &PathManager, metadata: &MetadataManager, key: &[u8]) -> bool {
+    match metadata.get_metadata_by_key(key) {
+        Ok(Some(meta)) => is_private_key(paths, metadata, key, &meta),
+        _ => true,
+    }
+}